@@ -0,0 +1,51 @@
+//! How long naming a cache entry takes next to compressing the data it saves.
+//! Run with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use halld::{compress_data, DataCache};
+use md5::{Digest, Md5};
+
+/// Somewhat compressible data, like a texture: runs of bytes broken up by noise
+fn asset(size: usize) -> Vec<u8> {
+    let mut state = 0x1234_5678u32;
+    let mut next = move || {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (state >> 16) as u8
+    };
+
+    let mut data = Vec::with_capacity(size);
+    while data.len() < size {
+        let (byte, run) = (next(), next() % 16 + 1);
+        data.extend(std::iter::repeat_n(byte, usize::from(run)));
+    }
+    data.truncate(size);
+
+    data
+}
+
+fn key_vs_compression(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cache");
+    // vpk0 takes over a second to compress 64 KiB, so fewer samples are plenty
+    group.sample_size(10);
+
+    for size in [4 << 10, 16 << 10, 64 << 10] {
+        let data = asset(size);
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("blake3 key", size), &data, |b, data| {
+            b.iter(|| DataCache::key(data, None))
+        });
+        // what keys used to be, for comparison
+        group.bench_with_input(BenchmarkId::new("md5", size), &data, |b, data| {
+            b.iter(|| Md5::digest(data))
+        });
+        group.bench_with_input(BenchmarkId::new("vpk0", size), &data, |b, data| {
+            b.iter(|| compress_data(data, None).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, key_vs_compression);
+criterion_main!(benches);
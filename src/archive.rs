@@ -0,0 +1,212 @@
+use halld::ObjectSettings;
+
+use anyhow::{anyhow, bail, Context, Result};
+use object::{read, Object, ObjectSection};
+
+/// The size in bytes of one entry in the file table
+const ENTRY_SIZE: usize = 12;
+
+/// A linked file table and its file data, as produced by `link::create_object`
+/// or as found in a retail ROM
+#[derive(Debug)]
+pub(crate) struct Archive {
+    entries: Vec<TableEntry>,
+    /// offset of the end of the last file, taken from the terminating table entry
+    end: u32,
+    data: Vec<u8>,
+}
+
+/// A decoded file table entry with all sizes and offsets in bytes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct TableEntry {
+    pub(crate) offset: u32,
+    pub(crate) compressed: bool,
+    pub(crate) inreloc: Option<u32>,
+    pub(crate) rom_size: u32,
+    pub(crate) exreloc: Option<u32>,
+    pub(crate) size: u32,
+}
+
+/// One file from an `Archive`, with its (possibly compressed) data and its extern file list
+#[derive(Debug)]
+pub(crate) struct ArchivedFile<'a> {
+    pub(crate) entry: TableEntry,
+    pub(crate) payload: &'a [u8],
+    pub(crate) externs: Vec<u16>,
+}
+
+impl Archive {
+    /// Read the file table and file data sections named in `sections` from a linked
+    /// ELF object
+    pub(crate) fn from_object(bytes: &[u8], sections: &ObjectSettings) -> Result<Self> {
+        let obj = read::File::parse(bytes).context("parsing linked object")?;
+        let section_data = |name: &str| {
+            obj.section_by_name(name)
+                .ok_or_else(|| anyhow!("missing {} section", name))
+                .and_then(|s| {
+                    s.data()
+                        .with_context(|| format!("reading {} section", name))
+                })
+        };
+
+        let table = section_data(sections.table_section_name())?;
+        let data = section_data(sections.files_section_name())?;
+
+        Self::parse(table, data.to_vec(), None)
+    }
+
+    /// Read a file table and file data that are already split apart
+    pub(crate) fn from_parts(table: &[u8], data: &[u8]) -> Result<Self> {
+        Self::parse(table, data.to_vec(), None)
+    }
+
+    /// Read a file table starting at `table_at` in `bytes` (like a ROM). The file data
+    /// is at `files_at`, or directly after the terminating table entry if `None`.
+    pub(crate) fn from_raw(
+        bytes: &[u8],
+        table_at: usize,
+        files_at: Option<usize>,
+        count: Option<usize>,
+    ) -> Result<Self> {
+        let table = bytes
+            .get(table_at..)
+            .ok_or_else(|| anyhow!("table offset {:#x} is outside of input", table_at))?;
+        let Self { entries, end, .. } = Self::parse(table, Vec::new(), count)?;
+
+        let files_at = files_at.unwrap_or(table_at + (entries.len() + 1) * ENTRY_SIZE);
+        let data = bytes
+            .get(files_at..files_at + end as usize)
+            .ok_or_else(|| {
+                anyhow!(
+                    "file data {:#x}-{:#x} is outside of input",
+                    files_at,
+                    files_at + end as usize
+                )
+            })?
+            .to_vec();
+
+        Ok(Self { entries, end, data })
+    }
+
+    /// Decode table entries until a terminating entry (the end offset followed by eight
+    /// zero bytes), or until `count` entries and the end entry have been read.
+    fn parse(table: &[u8], data: Vec<u8>, count: Option<usize>) -> Result<Self> {
+        let mut entries = Vec::with_capacity(count.unwrap_or(table.len() / ENTRY_SIZE));
+        let mut raw = table.chunks_exact(ENTRY_SIZE);
+
+        let end = loop {
+            let entry = raw
+                .next()
+                .ok_or_else(|| anyhow!("file table ended without a terminating entry"))?;
+            let at_end = match count {
+                Some(n) => entries.len() == n,
+                None => entry[4..].iter().all(|&b| b == 0),
+            };
+
+            if at_end {
+                break read_u32(entry, 0);
+            }
+            entries.push(TableEntry::from_bytes(entry));
+        };
+
+        Ok(Self { entries, end, data })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Get the data and extern file ids for file `id`
+    pub(crate) fn file(&self, id: usize) -> Result<ArchivedFile<'_>> {
+        let entry = *self
+            .entries
+            .get(id)
+            .ok_or_else(|| anyhow!("no file {} in table of {} files", id, self.len()))?;
+        let next = self.entries.get(id + 1).map_or(self.end, |e| e.offset);
+
+        let start = entry.offset as usize;
+        let data_end = start + entry.rom_size as usize;
+        let end = next as usize;
+        if data_end > end || end > self.data.len() {
+            bail!(
+                "file {} data {:#x}-{:#x} overlaps the next file at {:#x} or data end {:#x}",
+                id,
+                start,
+                data_end,
+                end,
+                self.data.len()
+            );
+        }
+
+        let payload = &self.data[start..data_end];
+        let extern_bytes = &self.data[data_end..end];
+        if !extern_bytes.len().is_multiple_of(2) {
+            bail!(
+                "file {} has an odd number of bytes ({}) for its extern file list",
+                id,
+                extern_bytes.len()
+            );
+        }
+        let externs = extern_bytes
+            .chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect();
+
+        Ok(ArchivedFile {
+            entry,
+            payload,
+            externs,
+        })
+    }
+}
+
+impl TableEntry {
+    fn from_bytes(b: &[u8]) -> Self {
+        let offset = read_u32(b, 0);
+        let read_words = |at| u32::from(read_u16(b, at)) * 4;
+        let read_opt = |at| match read_u16(b, at) {
+            0xFFFF => None,
+            words => Some(u32::from(words) * 4),
+        };
+
+        Self {
+            offset: offset & 0x7FFF_FFFF,
+            compressed: offset & 0x8000_0000 != 0,
+            inreloc: read_opt(4),
+            rom_size: read_words(6),
+            exreloc: read_opt(8),
+            size: read_words(10),
+        }
+    }
+}
+
+/// Follow a chain of HAL relocations starting at byte offset `start` in the
+/// uncompressed file `data`. Returns `(location, target)` pairs in bytes.
+pub(crate) fn decode_reloc_chain(data: &[u8], start: Option<u32>) -> Result<Vec<(u32, u32)>> {
+    let mut chain = Vec::new();
+    let mut next = start;
+
+    while let Some(loc) = next {
+        if chain.iter().any(|&(l, _)| l == loc) {
+            bail!("relocation chain loops back to {:#x}", loc);
+        }
+        let word = data
+            .get(loc as usize..loc as usize + 4)
+            .ok_or_else(|| anyhow!("relocation at {:#x} is outside of file data", loc))?;
+        let link = read_u16(word, 0);
+        let target = u32::from(read_u16(word, 2)) * 4;
+
+        chain.push((loc, target));
+        next = (link != 0xFFFF).then(|| u32::from(link) * 4);
+    }
+
+    Ok(chain)
+}
+
+fn read_u32(b: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+fn read_u16(b: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes(b[at..at + 2].try_into().unwrap())
+}
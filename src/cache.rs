@@ -1,29 +1,375 @@
-use md5::{Digest, Md5};
-use std::{fs, io, path::PathBuf};
+use crate::VpkSettings;
 
-pub(crate) struct DataCache {
-    dir: PathBuf,
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+mod http;
+mod local;
+
+pub use http::HttpBackend;
+pub use local::FsBackend;
+
+/// The vpk0 encoder that compressed the cached data. Keep this in sync with `Cargo.lock`
+/// (a test checks it), since a different encoder can compress the same data differently.
+const ENCODER: &str = "vpk0 0.8.2";
+
+/// Backends store entries under this directory, so a cache made by another version of
+/// `halld`, with different keys or entries, is never read. Caches from before this had
+/// entries named by an MD5 hash at the top of the directory; those are left alone until
+/// they are pruned.
+const LAYOUT: &str = "v2";
+
+/// Each `.bin` entry starts with this tag, then the length and BLAKE3 hash of the
+/// compressed data
+const MAGIC: &[u8; 4] = b"HALC";
+const HEADER_LEN: usize = MAGIC.len() + 4 + blake3::OUT_LEN;
+
+/// The hits and misses of the last link are saved in the cache as this file
+const LAST_RUN: &str = "last-run.json";
+
+/// Somewhere to store cache entries. Each entry is named by a key, and is made of
+/// the compressed data and the [`CacheManifest`] of the input that produced it.
+pub trait CacheBackend: Send + Sync {
+    /// The manifest and compressed data of the entry named `key`,
+    /// or `None` if there isn't a valid entry with that name
+    fn get(&self, key: &str) -> io::Result<Option<(CacheManifest, Vec<u8>)>>;
+
+    /// Store the entry named `key`
+    fn put(&self, key: &str, manifest: &CacheManifest, compressed: &[u8]) -> io::Result<()>;
+}
+
+/// A directory of vpk0 compressed data. Each entry is named by a [key](DataCache::key)
+/// and has a manifest with the input that produced it. See [`FsBackend`] for how entries
+/// are stored.
+///
+/// A cache can also have a remote backend, like an [`HttpBackend`], to share entries
+/// between machines. Entries missing from the directory are looked up in the remote
+/// backend, and new entries are stored in both. If the remote backend fails, a warning
+/// is printed and the rest of the link only uses the directory. A backend that can't
+/// be written to is still read from, so a read-only server works.
+pub struct DataCache {
+    local: FsBackend,
+    remote: Option<Box<dyn CacheBackend>>,
+    /// set once reading from the remote backend fails
+    remote_reads_failed: AtomicBool,
+    /// set once writing to the remote backend fails
+    remote_writes_failed: AtomicBool,
+    run: Mutex<RunLog>,
+}
+
+#[derive(Debug, Default)]
+struct RunLog {
+    stats: RunStats,
+    /// every entry read or written
+    used: HashSet<String>,
+}
+
+/// How many compressed files were found in the cache during a link
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RunStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+/// The size of a cache, and the hit rate of the last link that used it
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    pub entries: usize,
+    /// Size of the compressed data and manifests
+    pub bytes: u64,
+    pub last_run: Option<RunStats>,
+    /// Entries from before the cache layout was versioned, which are never read
+    pub legacy_entries: usize,
+    pub legacy_bytes: u64,
+}
+
+/// The entries deleted by [`DataCache::prune`] or [`DataCache::retain`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Removed {
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+/// What produced a cache entry; stored next to the compressed data as `<key>.json`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheManifest {
+    /// The file with the uncompressed data
+    pub input: PathBuf,
+    /// Size of the uncompressed data
+    pub size: usize,
+    pub method: u8,
+    pub offsets: Option<String>,
+    pub lengths: Option<String>,
+    pub encoder: String,
+}
+
+impl CacheManifest {
+    fn new(input: &Path, size: usize, settings: Option<&VpkSettings>) -> Self {
+        Self {
+            input: input.to_path_buf(),
+            size,
+            method: settings.and_then(|s| s.method).unwrap_or(0),
+            offsets: settings.and_then(|s| s.offsets.clone()),
+            lengths: settings.and_then(|s| s.lengths.clone()),
+            encoder: ENCODER.to_string(),
+        }
+    }
+
+    /// Was the entry compressed from data of `size` bytes with the same settings and encoder?
+    fn matches(&self, size: usize, settings: Option<&VpkSettings>) -> bool {
+        let other = Self::new(&self.input, size, settings);
+        *self == other
+    }
 }
 
 impl DataCache {
-    pub(crate) fn new(dir: PathBuf) -> io::Result<Self> {
-        fs::create_dir_all(&dir).map(|_| Self { dir })
+    /// Use `dir` as the cache, creating it if needed
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        FsBackend::new(dir).map(Self::with_local)
+    }
+
+    /// Use the existing cache in `dir` without creating anything, to report on or clean it up
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        FsBackend::open(dir).map(Self::with_local)
     }
 
-    pub(crate) fn read(&self, data: &[u8]) -> Option<Vec<u8>> {
-        let store = self.name_data(data);
-        fs::read(store).ok()
+    fn with_local(local: FsBackend) -> Self {
+        Self {
+            local,
+            remote: None,
+            remote_reads_failed: AtomicBool::new(false),
+            remote_writes_failed: AtomicBool::new(false),
+            run: Mutex::default(),
+        }
     }
 
-    pub(crate) fn write(&self, raw: &[u8], data: &[u8]) -> io::Result<()> {
-        let store = self.name_data(raw);
-        fs::write(store, data)
+    /// Share entries through the `remote` backend
+    pub fn with_remote<B: CacheBackend + 'static>(mut self, remote: B) -> Self {
+        self.remote = Some(Box::new(remote));
+        self
     }
 
-    fn name_data(&self, data: &[u8]) -> PathBuf {
-        let hash = Md5::new().chain_update(data).finalize();
-        let fname = format!("{}.bin", base16ct::lower::encode_string(&hash));
+    /// Get the data compressed from the uncompressed `data` with `settings`, if it was cached
+    pub fn read(&self, data: &[u8], settings: Option<&VpkSettings>) -> Option<Vec<u8>> {
+        let key = Self::key(data, settings);
+        let valid =
+            |(manifest, _): &(CacheManifest, Vec<u8>)| manifest.matches(data.len(), settings);
+
+        // a local entry that can't be read is treated like a missing one
+        let found = match self.local.get(&key).ok().flatten().filter(valid) {
+            Some((_, compressed)) => Some(compressed),
+            None => self
+                .remote_get(&key)
+                .filter(valid)
+                .map(|(manifest, compressed)| {
+                    // failing to keep a local copy only means asking the remote again next time
+                    let _ = self.local.put(&key, &manifest, &compressed);
+                    compressed
+                }),
+        };
 
-        self.dir.join(fname)
+        let mut run = self.run.lock().unwrap();
+        if found.is_some() {
+            run.stats.hits += 1;
+            run.used.insert(key);
+        } else {
+            run.stats.misses += 1;
+        }
+
+        found
     }
+
+    /// Cache the `compressed` data for the uncompressed `raw` data from `input`,
+    /// compressed with `settings`
+    pub fn write(
+        &self,
+        raw: &[u8],
+        settings: Option<&VpkSettings>,
+        input: &Path,
+        compressed: &[u8],
+    ) -> io::Result<()> {
+        let key = Self::key(raw, settings);
+        let manifest = CacheManifest::new(input, raw.len(), settings);
+
+        self.local.put(&key, &manifest, compressed)?;
+        self.remote_put(&key, &manifest, compressed);
+        self.run.lock().unwrap().used.insert(key);
+
+        Ok(())
+    }
+
+    /// The names of the entries read or written since the cache was opened
+    pub fn used(&self) -> Vec<String> {
+        let mut used = self
+            .run
+            .lock()
+            .unwrap()
+            .used
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        used.sort_unstable();
+        used
+    }
+
+    /// Save the hits and misses since the cache was opened, for [`DataCache::stats`]
+    pub fn save_run_stats(&self) -> io::Result<()> {
+        let stats = self.run.lock().unwrap().stats;
+        fs::write(
+            self.local.root().join(LAST_RUN),
+            serde_json::to_vec(&stats)?,
+        )
+    }
+
+    /// Count the entries in the cache directory and their size,
+    /// and read the stats of the last link
+    pub fn stats(&self) -> io::Result<CacheStats> {
+        let entries = self.local.entries()?;
+        let legacy = self.local.legacy_entries()?;
+        let last_run = fs::read(self.local.root().join(LAST_RUN))
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok());
+
+        Ok(CacheStats {
+            entries: entries.len(),
+            bytes: entries.iter().map(|e| e.bytes).sum(),
+            last_run,
+            legacy_entries: legacy.len(),
+            legacy_bytes: legacy.iter().map(|e| e.bytes).sum(),
+        })
+    }
+
+    /// Delete the least recently used entries in the cache directory until it is
+    /// at most `max_size` bytes. Legacy entries are never read, so they go first.
+    /// Files left by writers that stopped partway are always deleted.
+    pub fn prune(&self, max_size: u64) -> io::Result<Removed> {
+        let mut removed = Removed::default();
+        self.local.sweep(&mut removed)?;
+
+        let mut recent = self.local.entries()?;
+        recent.sort_unstable_by_key(|e| e.accessed);
+        let mut entries = self.local.legacy_entries()?;
+        entries.extend(recent);
+
+        let mut size = entries.iter().map(|e| e.bytes).sum::<u64>();
+        for entry in entries {
+            if size <= max_size {
+                break;
+            }
+            self.local.remove(&entry, &mut removed)?;
+            size -= entry.bytes;
+        }
+
+        Ok(removed)
+    }
+
+    /// Delete every entry in the cache directory that isn't named in `keep`,
+    /// every legacy entry, and the files left by writers that stopped partway
+    pub fn retain(&self, keep: &HashSet<String>) -> io::Result<Removed> {
+        let mut removed = Removed::default();
+        self.local.sweep(&mut removed)?;
+        for entry in self.local.legacy_entries()? {
+            self.local.remove(&entry, &mut removed)?;
+        }
+        for entry in self.local.entries()? {
+            if !keep.contains(&entry.key) {
+                self.local.remove(&entry, &mut removed)?;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn remote_get(&self, key: &str) -> Option<(CacheManifest, Vec<u8>)> {
+        let remote = self.remote.as_ref()?;
+        if self.remote_reads_failed.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        remote.get(key).unwrap_or_else(|e| {
+            // a server that can't be read from likely can't be written to either
+            self.remote_writes_failed.store(true, Ordering::Relaxed);
+            if !self.remote_reads_failed.swap(true, Ordering::Relaxed) {
+                eprintln!(
+                    "warning: can't read from the remote cache, \
+                    so files will be compressed and cached locally: {}",
+                    e
+                );
+            }
+            None
+        })
+    }
+
+    fn remote_put(&self, key: &str, manifest: &CacheManifest, compressed: &[u8]) {
+        let Some(remote) = self.remote.as_ref() else {
+            return;
+        };
+        if self.remote_writes_failed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        if let Err(e) = remote.put(key, manifest, compressed) {
+            if !self.remote_writes_failed.swap(true, Ordering::Relaxed) {
+                eprintln!(
+                    "warning: can't write to the remote cache, \
+                    so new entries will only be stored locally: {}",
+                    e
+                );
+            }
+        }
+    }
+
+    /// The name of the entry for `data` compressed with `settings`: a BLAKE3 hash of
+    /// everything that affects the compressed data. The excess bytes are added after
+    /// compression, so they aren't part of the key.
+    pub fn key(data: &[u8], settings: Option<&VpkSettings>) -> String {
+        let method = settings.and_then(|s| s.method).unwrap_or(0);
+        let tree = |tree: Option<&str>| match tree {
+            // a leading byte separates a missing tree from an empty one
+            Some(t) => [&[1][..], t.as_bytes(), &[0]].concat(),
+            None => vec![0],
+        };
+
+        blake3::Hasher::new()
+            .update(ENCODER.as_bytes())
+            .update(&[0, method])
+            .update(&tree(settings.and_then(|s| s.offsets.as_deref())))
+            .update(&tree(settings.and_then(|s| s.lengths.as_deref())))
+            .update(data)
+            .finalize()
+            .to_hex()
+            .to_string()
+    }
+}
+
+/// Add the header that [`unseal`] checks to the `compressed` data
+fn seal(compressed: &[u8]) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(HEADER_LEN + compressed.len());
+    entry.extend_from_slice(MAGIC);
+    entry.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+    entry.extend_from_slice(blake3::hash(compressed).as_bytes());
+    entry.extend_from_slice(compressed);
+
+    entry
+}
+
+/// The compressed data in a `.bin` entry, if it has the length and hash in its header
+fn unseal(entry: &[u8]) -> Option<&[u8]> {
+    let (header, compressed) = entry.split_at_checked(HEADER_LEN)?;
+    let (magic, header) = header.split_at(MAGIC.len());
+    let (len, hash) = header.split_at(4);
+    let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+
+    (magic == MAGIC && len == compressed.len() && blake3::hash(compressed).as_bytes() == hash)
+        .then_some(compressed)
 }
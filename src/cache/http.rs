@@ -0,0 +1,77 @@
+use super::{seal, unseal, CacheBackend, CacheManifest, LAYOUT};
+
+use std::{
+    io::{self, Read},
+    time::Duration,
+};
+
+/// Cache entries on an HTTP server, at `<url>/v2/<key>.bin` and `<url>/v2/<key>.json` like
+/// an [`FsBackend`](super::FsBackend) directory. Entries are read with `GET` and stored
+/// with `PUT`, so any server that stores what is `PUT` to it will work.
+///
+/// A `404` means there is no entry. A corrupt entry is treated the same way, since
+/// it can be replaced by the next `PUT`.
+pub struct HttpBackend {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl HttpBackend {
+    /// Use the server at `url`
+    pub fn new(url: &str) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(5))
+            .timeout(Duration::from_secs(30))
+            .build();
+
+        Self {
+            url: format!("{}/{}", url.trim_end_matches('/'), LAYOUT),
+            agent,
+        }
+    }
+
+    fn get_file(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match self.agent.get(&format!("{}/{}", self.url, name)).call() {
+            Ok(resp) => {
+                let mut contents = Vec::new();
+                resp.into_reader().read_to_end(&mut contents)?;
+                Ok(Some(contents))
+            }
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+
+    fn put_file(&self, name: &str, contents: &[u8]) -> io::Result<()> {
+        self.agent
+            .put(&format!("{}/{}", self.url, name))
+            .send_bytes(contents)
+            .map(drop)
+            .map_err(io::Error::other)
+    }
+}
+
+impl CacheBackend for HttpBackend {
+    fn get(&self, key: &str) -> io::Result<Option<(CacheManifest, Vec<u8>)>> {
+        let Some(json) = self.get_file(&format!("{}.json", key))? else {
+            return Ok(None);
+        };
+        let Some(entry) = self.get_file(&format!("{}.bin", key))? else {
+            return Ok(None);
+        };
+
+        let manifest = serde_json::from_slice(&json).ok();
+        Ok(manifest
+            .zip(unseal(&entry))
+            .map(|(manifest, compressed)| (manifest, compressed.to_vec())))
+    }
+
+    fn put(&self, key: &str, manifest: &CacheManifest, compressed: &[u8]) -> io::Result<()> {
+        // the manifest is read first, so only add it once the data is there
+        self.put_file(&format!("{}.bin", key), &seal(compressed))?;
+        self.put_file(
+            &format!("{}.json", key),
+            &serde_json::to_vec_pretty(manifest)?,
+        )
+    }
+}
@@ -0,0 +1,232 @@
+use super::{seal, unseal, CacheBackend, CacheManifest, Removed, LAYOUT};
+
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime},
+};
+
+/// Numbers the temporary files written by this process
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// A temporary file or a manifest without its data this old was left by a writer
+/// that stopped, rather than one that is still writing
+const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Cache entries in a directory, as `v2/<key>.bin` for the compressed data and
+/// `v2/<key>.json` for the manifest.
+///
+/// The directory can be shared by threads and by other processes. Entries are written
+/// to a temporary file and renamed into place, and the compressed data is checked against
+/// its length and hash when read. An entry that fails the check is deleted.
+/// Reading an entry updates its modified time, so the least recently used entries
+/// can be pruned.
+pub struct FsBackend {
+    root: PathBuf,
+    /// `root` joined with the layout version
+    dir: PathBuf,
+}
+
+/// A cache entry on disk
+pub(super) struct Entry {
+    /// the compressed data; the manifest is next to it
+    path: PathBuf,
+    pub(super) key: String,
+    pub(super) bytes: u64,
+    pub(super) accessed: SystemTime,
+}
+
+impl FsBackend {
+    /// Use `root` for entries, creating it if needed
+    pub fn new(root: PathBuf) -> io::Result<Self> {
+        let dir = root.join(LAYOUT);
+        fs::create_dir_all(&dir).map(|_| Self { root, dir })
+    }
+
+    /// Use the entries already in `root`, which must exist. Nothing is created.
+    pub fn open(root: PathBuf) -> io::Result<Self> {
+        fs::read_dir(&root)?;
+        let dir = root.join(LAYOUT);
+        Ok(Self { root, dir })
+    }
+
+    pub(super) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The entries in the layout's directory, which an opened cache may not have yet
+    pub(super) fn entries(&self) -> io::Result<Vec<Entry>> {
+        match Self::entries_in(&self.dir, |_| true) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            entries => entries,
+        }
+    }
+
+    /// The MD5-named entries from before the layout was versioned. The cache directory
+    /// may have other files in it, so only names of 32 lowercase hex digits are entries.
+    pub(super) fn legacy_entries(&self) -> io::Result<Vec<Entry>> {
+        let is_md5 = |key: &str| {
+            key.len() == 32 && key.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        };
+        Self::entries_in(&self.root, is_md5)
+    }
+
+    /// The `.bin` files in `dir` named by a key that passes `is_key`
+    fn entries_in(dir: &Path, is_key: impl Fn(&str) -> bool) -> io::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for file in fs::read_dir(dir)? {
+            let path = file?.path();
+            if path.extension().is_none_or(|ext| ext != "bin") {
+                continue;
+            }
+            let Some(key) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .filter(|&k| is_key(k))
+            else {
+                continue;
+            };
+            let key = key.to_string();
+
+            // another process may have removed the entry since the directory was read
+            let meta = match fs::metadata(&path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                meta => meta?,
+            };
+            if !meta.is_file() {
+                continue;
+            }
+            let manifest = fs::metadata(path.with_extension("json")).map_or(0, |m| m.len());
+            entries.push(Entry {
+                bytes: meta.len() + manifest,
+                accessed: meta.modified()?,
+                path,
+                key,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Delete an entry, unless another process already deleted it
+    pub(super) fn remove(&self, entry: &Entry, removed: &mut Removed) -> io::Result<()> {
+        let found = remove_if_found(&entry.path)?;
+        remove_if_found(&entry.path.with_extension("json"))?;
+        if found {
+            removed.entries += 1;
+            removed.bytes += entry.bytes;
+        }
+
+        Ok(())
+    }
+
+    /// Delete the temporary files and the manifests without data that writers
+    /// left behind when they stopped partway. Their size is added to `removed`.
+    pub(super) fn sweep(&self, removed: &mut Removed) -> io::Result<()> {
+        let files = match fs::read_dir(&self.dir) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            files => files?,
+        };
+        let now = SystemTime::now();
+        for file in files {
+            let path = file?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let temp = name.starts_with('.') && name.ends_with(".tmp");
+            let orphan = path.extension().is_some_and(|ext| ext == "json")
+                && !path.with_extension("bin").exists();
+            if !temp && !orphan {
+                continue;
+            }
+
+            let meta = match fs::metadata(&path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                meta => meta?,
+            };
+            let age = now.duration_since(meta.modified()?).unwrap_or_default();
+            if age >= STALE_AFTER && remove_if_found(&path)? {
+                removed.bytes += meta.len();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write `contents` to a temporary file next to `path`, then rename it to `path`,
+    /// so that no one can read a partly written file
+    fn write_file(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp = self.dir.join(format!(
+            ".{}.{}-{}.tmp",
+            name,
+            process::id(),
+            TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        fs::write(&temp, contents)
+            .and_then(|_| fs::rename(&temp, path))
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&temp);
+            })
+    }
+
+    /// Delete a corrupt entry. Another reader may have already deleted it,
+    /// so this doesn't fail.
+    fn discard(&self, key: &str) {
+        let _ = fs::remove_file(self.bin_path(key));
+        let _ = fs::remove_file(self.json_path(key));
+    }
+
+    fn bin_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.bin", key))
+    }
+
+    fn json_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+impl CacheBackend for FsBackend {
+    fn get(&self, key: &str) -> io::Result<Option<(CacheManifest, Vec<u8>)>> {
+        let read = |path: PathBuf| match fs::read(path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+        let (Some(json), Some(entry)) = (read(self.json_path(key))?, read(self.bin_path(key))?)
+        else {
+            return Ok(None);
+        };
+
+        let manifest = serde_json::from_slice(&json).ok();
+        let Some((manifest, compressed)) = manifest.zip(unseal(&entry)) else {
+            self.discard(key);
+            return Ok(None);
+        };
+
+        // only used to find old entries, so it doesn't matter if this fails
+        let _ = File::options()
+            .write(true)
+            .open(self.bin_path(key))
+            .and_then(|f| f.set_modified(SystemTime::now()));
+
+        Ok(Some((manifest, compressed.to_vec())))
+    }
+
+    fn put(&self, key: &str, manifest: &CacheManifest, compressed: &[u8]) -> io::Result<()> {
+        // an entry is only read once it has its manifest, so write that first
+        self.write_file(&self.json_path(key), &serde_json::to_vec_pretty(manifest)?)?;
+        self.write_file(&self.bin_path(key), &seal(compressed))
+    }
+}
+
+/// Delete `path`, returning whether it was there to delete
+fn remove_if_found(path: &Path) -> io::Result<bool> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,114 @@
+use halld::{DataCache, Linker, Removed};
+
+use std::{collections::HashSet, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+/// A `halld cache` command
+#[derive(Debug)]
+pub(crate) enum CacheCmd {
+    Stats,
+    Prune { max_size: u64 },
+    Gc { script: PathBuf },
+}
+
+/// Report on or clean up the cache directory in `opts`
+pub(crate) fn run(opts: crate::CacheOpt) -> Result<()> {
+    let crate::CacheOpt { cache, search, cmd } = opts;
+    let dir = cache.clone();
+    // reporting on or cleaning up a cache never creates one, so a mistyped path fails
+    let data_cache =
+        DataCache::open(cache).with_context(|| format!("opening cache < {} >", dir.display()))?;
+
+    match cmd {
+        CacheCmd::Stats => {
+            let stats = data_cache.stats().context("reading cache")?;
+            println!("entries: {}", stats.entries);
+            println!("size:    {}", fmt_size(stats.bytes));
+            match stats.last_run {
+                Some(run) if run.hits + run.misses > 0 => {
+                    let total = run.hits + run.misses;
+                    println!(
+                        "last run: {} of {} compressed files found ({:.1}%)",
+                        run.hits,
+                        total,
+                        run.hits as f64 / total as f64 * 100.0
+                    );
+                }
+                _ => println!("last run: no compressed files"),
+            }
+            if stats.legacy_entries > 0 {
+                println!(
+                    "{} entries ({}) are from an older halld and are never read; \
+                    delete them with `halld cache prune` or `halld cache gc`",
+                    stats.legacy_entries,
+                    fmt_size(stats.legacy_bytes)
+                );
+            }
+        }
+        CacheCmd::Prune { max_size } => {
+            let removed = data_cache.prune(max_size).context("pruning cache")?;
+            print_removed(removed);
+        }
+        CacheCmd::Gc { script } => {
+            // the keys only need the relocated data, so nothing is compressed
+            let config = crate::read_config(&script)?;
+            let keys = Linker::new(config)
+                .search_dirs(search.unwrap_or_default())
+                .cache_keys()
+                .with_context(|| format!("finding cache keys for < {} >", script.display()))?;
+            if keys.is_empty() {
+                bail!(
+                    "< {} > has no compressed files, so every entry would be deleted",
+                    script.display()
+                );
+            }
+
+            let keep = keys.into_iter().collect::<HashSet<_>>();
+            let removed = data_cache
+                .retain(&keep)
+                .context("deleting unused entries")?;
+            print_removed(removed);
+        }
+    }
+
+    Ok(())
+}
+
+fn print_removed(removed: Removed) {
+    println!(
+        "removed {} entries ({})",
+        removed.entries,
+        fmt_size(removed.bytes)
+    );
+}
+
+fn fmt_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Parse a size in bytes, with an optional `K`, `M`, or `G` suffix for KiB, MiB, or GiB
+pub(crate) fn parse_size(s: &str) -> Result<u64> {
+    let (num, shift) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 30),
+        _ => (s, 0),
+    };
+    let num = crate::parse_offset(num)?.try_into()?;
+
+    u64::checked_mul(num, 1 << shift).with_context(|| format!("size \"{}\" is too large", s))
+}
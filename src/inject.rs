@@ -0,0 +1,161 @@
+use halld::Linker;
+
+use std::{fs, ops::Range};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+/// The first word of a big endian (.z64) N64 ROM
+const Z64_MAGIC: [u8; 4] = [0x80, 0x37, 0x12, 0x40];
+
+/// Link a script and write its file table and data into an existing ROM
+pub(crate) fn run(opts: crate::InjectOpt) -> Result<()> {
+    let crate::InjectOpt {
+        config,
+        search,
+        cache,
+        remote_cache,
+        rom,
+        output,
+        files_at,
+        table_at,
+        limit,
+        cic,
+    } = opts;
+
+    let script = crate::read_config(&config)?;
+    let linked = Linker::new(script)
+        .search_dirs(search.unwrap_or_default())
+        .optional_cache(cache)
+        .optional_remote_cache(remote_cache)
+        .link()?;
+
+    let mut image = fs::read(&rom).with_context(|| format!("reading ROM < {} >", rom.display()))?;
+    if !image.starts_with(&Z64_MAGIC) {
+        bail!(
+            "< {} > is not a big endian (.z64) N64 ROM; convert it before injecting",
+            rom.display()
+        );
+    }
+
+    let table = table_at..table_at + linked.table.len();
+    let files = files_at..files_at + linked.data.len();
+    check_regions(&table, &files, limit)?;
+
+    let end = table.end.max(files.end);
+    if end > image.len() {
+        eprintln!(
+            "growing ROM from {:#x} to {:#x} bytes",
+            image.len(),
+            end.next_multiple_of(4)
+        );
+        image.resize(end.next_multiple_of(4), 0);
+    }
+    image[table].copy_from_slice(&linked.table);
+    image[files].copy_from_slice(&linked.data);
+
+    if let Some(cic) = cic {
+        let (crc1, crc2) = n64_crc(&image, cic)?;
+        image[0x10..0x14].copy_from_slice(&crc1.to_be_bytes());
+        image[0x14..0x18].copy_from_slice(&crc2.to_be_bytes());
+    }
+
+    let output = output.unwrap_or(rom);
+    fs::write(&output, image).with_context(|| format!("writing ROM < {} >", output.display()))
+}
+
+/// Refuse to write the table over the data, or either of them past `limit`
+fn check_regions(table: &Range<usize>, files: &Range<usize>, limit: Option<usize>) -> Result<()> {
+    if table.start < files.end && files.start < table.end {
+        bail!(
+            "the file table at {:#x}-{:#x} overlaps the file data at {:#x}-{:#x}",
+            table.start,
+            table.end,
+            files.start,
+            files.end
+        );
+    }
+
+    if let Some(limit) = limit {
+        for (name, region) in [("file table", table), ("file data", files)] {
+            if region.end > limit {
+                bail!(
+                    "the {} at {:#x}-{:#x} would write past the limit of {:#x} by {:#x} bytes",
+                    name,
+                    region.start,
+                    region.end,
+                    limit,
+                    region.end - limit
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Calculate the two header checksums of a ROM that boots with CIC-NUS-`cic`
+fn n64_crc(rom: &[u8], cic: u32) -> Result<(u32, u32)> {
+    const START: usize = 0x1000;
+    const LENGTH: usize = 0x10_0000;
+
+    let seed: u32 = match cic {
+        6101 | 6102 => 0xF8CA_4DDC,
+        6103 => 0xA388_6759,
+        6105 => 0xDF26_F436,
+        6106 => 0x1FEA_617A,
+        _ => bail!(
+            "unknown CIC {}; expected 6101, 6102, 6103, 6105, or 6106",
+            cic
+        ),
+    };
+    if rom.len() < START + LENGTH {
+        bail!(
+            "the ROM is {:#x} bytes, but the checksum covers up to {:#x}",
+            rom.len(),
+            START + LENGTH
+        );
+    }
+
+    let word = |at: usize| u32::from_be_bytes(rom[at..at + 4].try_into().unwrap());
+    let [mut t1, mut t2, mut t3, mut t4, mut t5, mut t6] = [seed; 6];
+
+    for i in (START..START + LENGTH).step_by(4) {
+        let d = word(i);
+        if t6.checked_add(d).is_none() {
+            t4 = t4.wrapping_add(1);
+        }
+        t6 = t6.wrapping_add(d);
+        t3 ^= d;
+        let r = d.rotate_left(d & 0x1F);
+        t5 = t5.wrapping_add(r);
+        if t2 > d {
+            t2 ^= r;
+        } else {
+            t2 ^= t6 ^ d;
+        }
+
+        t1 = match cic {
+            // reads from the boot code
+            6105 => t1.wrapping_add(word(0x0750 + (i & 0xFF)) ^ d),
+            _ => t1.wrapping_add(t5 ^ d),
+        };
+    }
+
+    let crcs = match cic {
+        6103 => ((t6 ^ t4).wrapping_add(t3), (t5 ^ t2).wrapping_add(t1)),
+        6106 => (
+            t6.wrapping_mul(t4).wrapping_add(t3),
+            t5.wrapping_mul(t2).wrapping_add(t1),
+        ),
+        _ => (t6 ^ t4 ^ t3, t5 ^ t2 ^ t1),
+    };
+
+    Ok(crcs)
+}
+
+/// Parse a CIC chip number, like `6102` or `CIC-NUS-6102`
+pub(crate) fn parse_cic(s: &str) -> Result<u32> {
+    s.trim_start_matches("CIC-NUS-")
+        .parse()
+        .map_err(|e| anyhow!("invalid CIC \"{}\": {}", s, e))
+}
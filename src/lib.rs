@@ -1,18 +1,118 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::path::PathBuf;
 
+mod cache;
+mod link;
+
+pub use cache::{
+    CacheBackend, CacheManifest, CacheStats, DataCache, FsBackend, HttpBackend, Removed, RunStats,
+};
+pub use link::{compress_data, CDefs, Diagnostic, Diagnostics, LinkOutput, Linker, Sym, SymMap};
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct LinkerConfig {
     pub settings: Option<LinkerSettings>,
     pub script: LinkerScript,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LinkerSettings {
     pub search_dirs: Option<Vec<PathBuf>>,
     pub output: Option<PathBuf>,
     pub cache: Option<PathBuf>,
+    /// URL of an HTTP server to share the cache through; needs a `cache` directory
+    pub remote_cache: Option<String>,
+    pub object: Option<ObjectSettings>,
+}
+
+/// Settings for the linked object
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ObjectSettings {
+    /// Relocate the table offsets and symbols against the file data section,
+    /// instead of using offsets from the start of the file data
+    pub relocatable: Option<bool>,
+    /// Name of the file table section; `.filetable` by default
+    pub table_section: Option<String>,
+    /// Name of the file data section; `.files` by default
+    pub files_section: Option<String>,
+    /// Alignment of the file table section; 4 by default
+    pub table_align: Option<u64>,
+    /// Alignment of the file data section; 4 by default
+    pub files_align: Option<u64>,
+    /// Symbols to define at the boundaries of the sections. For older scripts, `true`
+    /// defines `__files_start` and `__files_end`
+    #[serde(default, deserialize_with = "boundary_symbols")]
+    pub boundary_symbols: Option<BoundarySymbols>,
+    /// Symbols to define for each linked file
+    pub file_symbols: Option<FileSymbols>,
+}
+
+impl ObjectSettings {
+    /// The name of the file table section
+    pub fn table_section_name(&self) -> &str {
+        self.table_section.as_deref().unwrap_or(".filetable")
+    }
+
+    /// The name of the file data section
+    pub fn files_section_name(&self) -> &str {
+        self.files_section.as_deref().unwrap_or(".files")
+    }
+}
+
+/// Names of the symbols to define for the linked sections. A symbol is only
+/// defined if it has a name.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BoundarySymbols {
+    /// The start of the file table
+    pub table_start: Option<String>,
+    /// The end of the file table, after the terminating entry
+    pub table_end: Option<String>,
+    /// The start of the file data
+    pub files_start: Option<String>,
+    /// The end of the file data
+    pub files_end: Option<String>,
+    /// An absolute symbol with the number of files in the table
+    pub file_count: Option<String>,
+}
+
+/// Accept either the names of the boundary symbols, or the bool from before they
+/// could be named
+fn boundary_symbols<'de, D>(de: D) -> Result<Option<BoundarySymbols>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Setting {
+        Enabled(bool),
+        Names(BoundarySymbols),
+    }
+
+    Ok(match Option::<Setting>::deserialize(de)? {
+        Some(Setting::Names(names)) => Some(names),
+        Some(Setting::Enabled(true)) => Some(BoundarySymbols {
+            files_start: Some("__files_start".into()),
+            files_end: Some("__files_end".into()),
+            ..Default::default()
+        }),
+        Some(Setting::Enabled(false)) | None => None,
+    })
+}
+
+/// Name templates for the symbols defined for each linked file. In a template,
+/// `{name}` is replaced by the file's C define (`RLD_FID_...`) and `{id}` by its file id.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileSymbols {
+    /// The start of the file's data; `{name}_START` by default
+    pub start: Option<String>,
+    /// The end of the file's data, before its extern file ids; `{name}_END` by default
+    pub end: Option<String>,
+    /// The file's entry in the file table; `{name}_ENTRY` by default
+    pub entry: Option<String>,
 }
 
 pub type LinkerScript = Vec<InputFile>;
@@ -21,12 +121,130 @@ pub type LinkerScript = Vec<InputFile>;
 #[serde(rename_all = "camelCase")]
 pub struct InputFile {
     pub file: PathBuf,
+    /// Pin `file` to this id instead of the id after the previous file
+    pub id: Option<u16>,
     pub compressed: bool,
     pub comp_settings: Option<VpkSettings>,
     pub inreloc: Option<u32>,
     pub exreloc: Option<u32>,
-    pub exports: Option<Vec<(String, u32)>>,
-    pub imports: Option<Vec<u16>>,
+    pub exports: Option<Vec<Export>>,
+    pub imports: Option<Vec<FileRef>>,
+    /// Path to a JSON list of [`RawReloc`]s to build the relocation chains of a raw file
+    pub relocs: Option<PathBuf>,
+    /// Treat `file` as this type instead of checking its contents
+    #[serde(rename = "type")]
+    pub file_type: Option<FileType>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileType {
+    /// Binary data that is linked as is
+    Raw,
+    /// A 32-bit big endian MIPS relocatable object
+    Elf,
+}
+
+/// A symbol exported by a file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Export {
+    /// `[name, offset]` for a global data symbol with no size
+    Offset(String, u32),
+    Symbol(ExportedSymbol),
+    Alias(SymbolAlias),
+    /// A global symbol of an ELF object to export; the object's other symbols are
+    /// only in its namespace
+    Name(String),
+}
+
+impl From<(String, u32)> for Export {
+    fn from((name, addr): (String, u32)) -> Self {
+        Self::Offset(name, addr)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ExportedSymbol {
+    pub name: String,
+    /// Offset of the symbol in the file
+    pub addr: u32,
+    /// Size of the symbol in bytes; 0 by default
+    pub size: Option<u32>,
+    /// `data` by default
+    pub kind: Option<SymKind>,
+    /// `global` by default
+    pub scope: Option<SymScope>,
+    /// A weak symbol is replaced by a non-weak symbol with the same name; false by default
+    pub weak: Option<bool>,
+}
+
+/// Another name for a symbol, with the same address, size, and type
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SymbolAlias {
+    pub name: String,
+    /// The aliased symbol, as `symbol` or `file::symbol`
+    pub alias_of: String,
+    /// `global` by default
+    pub scope: Option<SymScope>,
+    /// false by default
+    pub weak: Option<bool>,
+}
+
+/// The type of a symbol in the output object
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymKind {
+    /// No type, like a plain assembler label
+    Unknown,
+    Data,
+    Text,
+}
+
+/// The binding and visibility of a symbol in the output object
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymScope {
+    /// Only usable in this link; a local symbol in the output
+    Local,
+    /// A global symbol with hidden visibility
+    Hidden,
+    /// A global symbol with default visibility
+    Global,
+}
+
+/// A reference to another file in the script
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FileRef {
+    /// The file's id
+    Id(u16),
+    /// The file's path as written in the script, its C define, or a symbol it exports
+    Name(String),
+}
+
+/// A pointer in a raw file that should be relocated
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RawReloc {
+    /// Offset of the pointer in the file
+    pub at: u32,
+    #[serde(flatten)]
+    pub target: RelocTarget,
+}
+
+/// Where a [`RawReloc`] points
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum RelocTarget {
+    /// An offset in the same file
+    Internal { to: u32 },
+    /// An exported symbol, plus an offset from that symbol
+    Symbol {
+        symbol: String,
+        #[serde(default)]
+        addend: i32,
+    },
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
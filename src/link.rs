@@ -1,97 +1,541 @@
-use halld::LinkerConfig;
+use crate::{
+    DataCache, FileType, HttpBackend, InputFile, LinkerConfig, LinkerSettings, ObjectSettings,
+    SymKind, SymScope,
+};
 use object::{
-    write::{self, SectionId, StandardSegment},
-    Architecture, BinaryFormat, Endianness, SectionKind,
+    elf,
+    write::{self, StandardSegment},
+    Architecture, BinaryFormat, Endianness, RelocationEncoding, RelocationKind, SectionKind,
 };
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap},
     fs::File,
-    io::{BufReader, BufWriter},
-    path::{Component, Path},
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
 };
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
 mod chdr;
+mod diag;
+mod formats;
+mod imports;
+mod layout;
+mod map;
+mod mips;
 mod mkdep;
+mod namespace;
 mod pass1;
 mod pass2;
 
+pub use diag::{Diagnostic, Diagnostics};
+pub use pass2::compress_data;
+
+/// A symbol defined by a linked file
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-struct Sym {
-    addr: u32,
-    file: usize,
-}
-
-type SymMap = HashMap<String, Sym>;
-type CDefs = Vec<(String, u16)>;
-
-pub(crate) fn run(opts: crate::RunOpt) -> Result<()> {
-    let crate::RunOpt {
-        config,
-        search,
-        output,
-        header,
-        mdep,
-        cache,
-    } = opts;
-
-    let rdr = BufReader::new(
-        File::open(&config)
-            .with_context(|| format!("couldn't open config script at <{}>", config.display()))?,
-    );
-
-    let LinkerConfig {
-        mut settings,
-        script,
-    } = serde_json::from_reader(rdr).context("parsing config JSON")?;
-
-    let config_output = settings.as_mut().and_then(|s| s.output.take());
-    let config_cache = settings.as_mut().and_then(|s| s.cache.take());
-    let config_search = settings.and_then(|s| s.search_dirs);
-
-    let output = output
-        .or(config_output)
-        .ok_or_else(|| anyhow!("no output location from JSON or from CLI"))?;
-
-    let cache = cache.or(config_cache);
-
-    let search_dirs = match (search, config_search) {
-        (Some(s), None) | (None, Some(s)) => Some(s),
-        (Some(mut a), Some(b)) => {
-            a.extend(b);
-            Some(a)
+pub struct Sym {
+    /// offset of the symbol from the start of its file
+    pub addr: u32,
+    /// id of the file that defines the symbol
+    pub file: usize,
+    /// size of the symbol in bytes
+    pub size: u32,
+    pub kind: SymKind,
+    pub scope: SymScope,
+    /// a weak symbol is replaced by a non-weak one with the same name
+    pub weak: bool,
+}
+
+/// Exported symbols by name
+pub type SymMap = HashMap<String, Sym>;
+/// The C define name and id for each file
+pub type CDefs = Vec<(String, u16)>;
+
+/// Link the files in a [`LinkerConfig`] into a file table and file data.
+///
+/// Search directories added to the `Linker` are checked before the ones in the config's
+/// settings, and a cache directory or remote cache added to the `Linker` overrides
+/// the config's.
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// let config: halld::LinkerConfig = serde_json::from_str(r#"{ "script": [] }"#)?;
+/// let output = halld::Linker::new(config)
+///     .search_dir("assets")
+///     .cache(".cache")
+///     .link()?;
+/// println!("{} files", output.file_ids.len());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Linker {
+    config: LinkerConfig,
+    search_dirs: Vec<PathBuf>,
+    cache: Option<PathBuf>,
+    remote_cache: Option<String>,
+}
+
+impl Linker {
+    pub fn new(config: LinkerConfig) -> Self {
+        Self {
+            config,
+            search_dirs: Vec::new(),
+            cache: None,
+            remote_cache: None,
         }
-        (None, None) => None,
-    };
+    }
+
+    /// Add a directory in which to search for the files in the script
+    pub fn search_dir<P: Into<PathBuf>>(&mut self, dir: P) -> &mut Self {
+        self.search_dirs.push(dir.into());
+        self
+    }
+
+    /// Add directories in which to search for the files in the script
+    pub fn search_dirs<I>(&mut self, dirs: I) -> &mut Self
+    where
+        I: IntoIterator,
+        I::Item: Into<PathBuf>,
+    {
+        self.search_dirs.extend(dirs.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set a directory to cache compressed data
+    pub fn cache<P: Into<PathBuf>>(&mut self, dir: P) -> &mut Self {
+        self.cache = Some(dir.into());
+        self
+    }
+
+    /// Set a directory to cache compressed data, if `dir` is `Some`
+    pub fn optional_cache(&mut self, dir: Option<PathBuf>) -> &mut Self {
+        if dir.is_some() {
+            self.cache = dir;
+        }
+        self
+    }
+
+    /// Share the cache through the HTTP server at `url`; see [`HttpBackend`]
+    pub fn remote_cache<S: Into<String>>(&mut self, url: S) -> &mut Self {
+        self.remote_cache = Some(url.into());
+        self
+    }
+
+    /// Share the cache through the HTTP server at `url`, if `url` is `Some`
+    pub fn optional_remote_cache(&mut self, url: Option<String>) -> &mut Self {
+        if url.is_some() {
+            self.remote_cache = url;
+        }
+        self
+    }
+
+    /// Link the script into a file table and file data
+    pub fn link(&self) -> Result<LinkOutput> {
+        let LinkerConfig { settings, script } = self.config.clone();
+        let settings = settings.unwrap_or_default();
+
+        let search_dirs = self.all_search_dirs(&settings);
+        let cache = self
+            .cache
+            .clone()
+            .or(settings.cache)
+            .map(DataCache::new)
+            .transpose()
+            .context("creating vpk compression cache")?;
+        let cache = match (cache, self.remote_cache.clone().or(settings.remote_cache)) {
+            (Some(cache), Some(url)) => Some(cache.with_remote(HttpBackend::new(&url))),
+            (None, Some(url)) => bail!("remote cache < {} > needs a cache directory", url),
+            (cache, None) => cache,
+        };
+
+        let p1 = pass1::Pass1::run(script, search_dirs).context("linker pass 1")?;
+        let p2 = pass2::Pass2::run(p1, cache)?;
+
+        let mut output = LinkOutput::from(p2);
+        output.object = settings.object.unwrap_or_default();
+        check_file_symbols(&output)?;
+
+        Ok(output)
+    }
+
+    /// Find the [cache key](DataCache::key) of every compressed file in the script,
+    /// without compressing anything or using a cache. Only problems that change the
+    /// data of a compressed file, like an undefined symbol in it, are errors.
+    pub fn cache_keys(&self) -> Result<Vec<String>> {
+        let LinkerConfig { settings, script } = self.config.clone();
+        let search_dirs = self.all_search_dirs(&settings.unwrap_or_default());
+
+        let p1 = pass1::Pass1::run(script, search_dirs).context("linker pass 1")?;
+        pass2::cache_keys(p1)
+    }
+
+    /// The search directories from the builder, then from the script's settings
+    fn all_search_dirs(&self, settings: &LinkerSettings) -> Option<Vec<PathBuf>> {
+        let mut search_dirs = self.search_dirs.clone();
+        search_dirs.extend(settings.search_dirs.iter().flatten().cloned());
+        (!search_dirs.is_empty()).then_some(search_dirs)
+    }
+}
+
+/// The result of a link
+#[derive(Debug)]
+pub struct LinkOutput {
+    /// the file table, terminated by an entry with the end offset of `data`
+    pub table: Vec<u8>,
+    /// the (possibly compressed) data of every file, each followed by its extern file ids
+    pub data: Vec<u8>,
+    /// every exported symbol
+    pub symbols: SymMap,
+    /// the symbols that are only in their file's namespace, sorted by file and address
+    pub local_symbols: Vec<(String, Sym)>,
+    /// the C define name and id of every file, sorted by id
+    pub file_ids: CDefs,
+    /// the resolved path of the file with each id, or `None` for an empty entry in a gap
+    pub inputs: Vec<Option<PathBuf>>,
+    /// the resolved path of every relocation file for a raw input
+    pub reloc_files: Vec<PathBuf>,
+    /// the names of the cache entries used for compressed files, if there is a cache
+    pub cache_keys: Vec<String>,
+    /// settings for [`LinkOutput::write_object`]
+    pub object: ObjectSettings,
+}
+
+impl LinkOutput {
+    /// Write a big endian MIPS ELF object with the table in `.filetable` and
+    /// the file data in `.files`, or the sections named in [`LinkOutput::object`]
+    pub fn write_object<W: Write>(&self, wtr: W) -> Result<()> {
+        create_object(self)?
+            .write_stream(wtr)
+            .map_err(|e| anyhow!("writing output object file: {}", e))
+    }
 
-    let p1 = pass1::Pass1::run(script, search_dirs).context("linker pass 1")?;
-    let p2 = pass2::Pass2::run(p1, cache)?;
+    /// Write the file table followed by the file data, which starts at the next multiple
+    /// of the data section alignment
+    pub fn write_bin<W: Write>(&self, wtr: &mut W) -> Result<()> {
+        formats::write_bin(wtr, self)
+    }
+
+    /// Write a GNU as source file with the file table and symbols. The file data is
+    /// included from `data_path` with `.incbin`, so `data` has to be written there.
+    pub fn write_asm<W: Write>(&self, wtr: &mut W, data_path: &Path) -> Result<()> {
+        formats::write_asm(wtr, self, data_path)
+    }
+
+    /// Write a C source file with the file table and file data as byte arrays.
+    /// The table offsets are never relocated.
+    pub fn write_c<W: Write>(&self, wtr: &mut W) -> Result<()> {
+        formats::write_c(wtr, self)
+    }
+
+    /// Write a C header with a define for each file id. The include guard
+    /// is based on the path of the linked object, `obj_path`.
+    pub fn write_c_header<W: Write>(&self, wtr: &mut W, obj_path: &Path) -> io::Result<()> {
+        chdr::write_c_header(wtr, obj_path, &self.file_ids)
+    }
 
-    if let Some(file) = header {
-        let mut wtr = BufWriter::new(File::create(file).context("creating c header file")?);
-        chdr::write_c_header(&mut wtr, &output, &p2.c_header)
-            .context("writing defines to c header")?;
+    /// Write a human readable map of every file's id, C define, path, table entry,
+    /// externs, and exported symbols
+    pub fn write_map<W: Write>(&self, wtr: &mut W) -> io::Result<()> {
+        map::write_map(wtr, &map::map_files(self))
     }
-    if let Some(p) = mdep {
-        let mut wtr = BufWriter::new(File::create(p).context("creating make dependencies file")?);
-        mkdep::write_make_dep(&mut wtr, &output, &config, &p2.inputs)
-            .context("writing dependencies to makefile")?;
+
+    /// Write the same information as [`LinkOutput::write_map`] as JSON
+    pub fn write_map_json<W: Write>(&self, wtr: W) -> Result<()> {
+        serde_json::to_writer_pretty(wtr, &map::map_files(self)).context("writing JSON link map")
+    }
+
+    /// Write a Makefile rule with `script`, every input, and every relocation file
+    /// as a dependency of `obj_path`
+    pub fn write_make_dep<W: Write>(
+        &self,
+        wtr: &mut W,
+        obj_path: &Path,
+        script: &Path,
+    ) -> io::Result<()> {
+        let inputs = self.inputs.iter().flatten();
+        let deps = inputs.chain(&self.reloc_files).cloned().collect::<Vec<_>>();
+        mkdep::write_make_dep(wtr, obj_path, script, &deps)
+    }
+}
+
+impl From<pass2::Pass2> for LinkOutput {
+    fn from(p2: pass2::Pass2) -> Self {
+        Self {
+            table: p2.table,
+            data: p2.data,
+            symbols: p2.symbols,
+            local_symbols: p2.local_symbols,
+            file_ids: p2.c_header,
+            inputs: p2.inputs,
+            reloc_files: p2.reloc_files,
+            cache_keys: p2.cache_keys,
+            object: ObjectSettings::default(),
+        }
+    }
+}
+
+/// Get the type of an input from the script, or by checking the file for an ELF header
+fn detect_file_type(entry: &InputFile) -> Result<FileType> {
+    const HEADER_SIZE: u64 = 20;
+
+    let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+    File::open(&entry.file)
+        .and_then(|f| f.take(HEADER_SIZE).read_to_end(&mut header))
+        .with_context(|| format!("reading header of < {} >", entry.file.display()))?;
+
+    let file_type = match entry.file_type {
+        Some(t) => t,
+        None if header.starts_with(&elf::ELFMAG) => FileType::Elf,
+        None => FileType::Raw,
+    };
+
+    if file_type == FileType::Elf {
+        check_elf_header(&header)
+            .with_context(|| format!("< {} > can't be linked", entry.file.display()))?;
+    }
+
+    Ok(file_type)
+}
+
+/// Check that `header` starts a 32-bit big endian MIPS relocatable object
+fn check_elf_header(header: &[u8]) -> Result<()> {
+    if header.len() < 20 || !header.starts_with(&elf::ELFMAG) {
+        bail!("not an ELF file");
     }
 
-    let obj = create_object(p2);
-    let wtr = BufWriter::new(File::create(output).context("making output file")?);
-    obj.write_stream(wtr).expect("writing output object file");
+    // e_ident: magic, class, data encoding, ...
+    let class = header[4];
+    let data = header[5];
+    let e_type = u16::from_be_bytes([header[16], header[17]]);
+    let e_machine = u16::from_be_bytes([header[18], header[19]]);
+
+    match (class, data) {
+        (elf::ELFCLASS32, elf::ELFDATA2MSB) => (),
+        (elf::ELFCLASS64, elf::ELFDATA2MSB) => {
+            bail!("it is a 64-bit ELF; only 32-bit MIPS objects are supported")
+        }
+        (_, elf::ELFDATA2LSB) => {
+            bail!("it is a little endian ELF; only big endian MIPS objects are supported")
+        }
+        _ => bail!("unknown ELF class {} or data encoding {}", class, data),
+    }
+    if e_machine != elf::EM_MIPS {
+        bail!("it is for ELF machine {} instead of MIPS", e_machine);
+    }
+    if e_type != elf::ET_REL {
+        bail!("it is ELF type {} instead of a relocatable object", e_type);
+    }
 
     Ok(())
 }
 
-fn is_object(p: impl AsRef<Path>) -> bool {
-    // todo: replace with something that checks for relocatable object?
-    p.as_ref().extension().map_or(false, |ex| ex == "o")
+/// The names and alignments of the file table and file data sections
+struct OutputSections<'a> {
+    table: &'a str,
+    files: &'a str,
+    table_align: u64,
+    files_align: u64,
+}
+
+impl<'a> OutputSections<'a> {
+    fn new(settings: &'a ObjectSettings) -> Result<Self> {
+        let table = settings.table_section_name();
+        let files = settings.files_section_name();
+        if table == files {
+            bail!(
+                "the file table and file data can't both be in section {}",
+                table
+            );
+        }
+
+        Ok(Self {
+            table,
+            files,
+            table_align: section_align(settings.table_align).context("file table alignment")?,
+            files_align: section_align(settings.files_align).context("file data alignment")?,
+        })
+    }
+}
+
+/// Where a symbol in the output is defined
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Placement {
+    /// an offset in the file table section
+    Table(u32),
+    /// an offset in the file data section
+    Files(u32),
+    Absolute(u32),
+}
+
+/// A symbol to define in the output
+#[derive(Debug, Clone)]
+struct OutputSymbol<'a> {
+    name: Cow<'a, str>,
+    place: Placement,
+    size: u32,
+    kind: SymKind,
+    scope: SymScope,
+    weak: bool,
+}
+
+/// Every exported, boundary, and per file symbol to define in the output
+fn output_symbols(out: &LinkOutput) -> Result<Vec<OutputSymbol<'_>>> {
+    let LinkOutput {
+        table,
+        data,
+        symbols,
+        local_symbols,
+        object: settings,
+        ..
+    } = out;
+    let relocatable = settings.relocatable.unwrap_or(false);
+
+    let locals = local_symbols.iter().map(|(name, sym)| (name, sym));
+    let exported = symbols.iter().chain(locals).map(|(name, sym)| {
+        let place = if !relocatable {
+            Placement::Files(sym.addr)
+        } else {
+            match table_offset(table, sym.file) {
+                (offset, false) => Placement::Files(offset + sym.addr),
+                // compressed data has to be loaded first, so only the offset is useful
+                (_, true) => Placement::Absolute(sym.addr),
+            }
+        };
+        OutputSymbol {
+            name: name.into(),
+            place,
+            size: sym.size,
+            kind: sym.kind,
+            scope: sym.scope,
+            weak: sym.weak,
+        }
+    });
+
+    let boundaries = settings.boundary_symbols.iter().flat_map(|names| {
+        let file_count = table.len() / 12 - 1;
+        [
+            (&names.table_start, Placement::Table(0)),
+            (&names.table_end, Placement::Table(table.len() as u32)),
+            (&names.files_start, Placement::Files(0)),
+            (&names.files_end, Placement::Files(data.len() as u32)),
+            (&names.file_count, Placement::Absolute(file_count as u32)),
+        ]
+        .into_iter()
+        .filter_map(|(name, place)| {
+            name.as_deref().map(|name| OutputSymbol {
+                name: name.into(),
+                place,
+                size: 0,
+                kind: SymKind::Unknown,
+                scope: SymScope::Global,
+                weak: false,
+            })
+        })
+    });
+
+    let per_file = file_symbols(out)?.into_iter().map(|(_, sym)| sym);
+
+    Ok(exported.chain(boundaries).chain(per_file).collect())
+}
+
+/// The start, end, and table entry symbols of every file, with the file's id
+fn file_symbols(out: &LinkOutput) -> Result<Vec<(u16, OutputSymbol<'_>)>> {
+    let LinkOutput {
+        table,
+        file_ids,
+        object: settings,
+        ..
+    } = out;
+
+    let mut per_file = Vec::new();
+    if let Some(templates) = settings.file_symbols.as_ref() {
+        let start = file_symbol_template(&templates.start, "{name}_START")?;
+        let end = file_symbol_template(&templates.end, "{name}_END")?;
+        let entry = file_symbol_template(&templates.entry, "{name}_ENTRY")?;
+
+        for (cident, id) in file_ids {
+            let name = |template: &str| {
+                let name = template
+                    .replace("{name}", cident)
+                    .replace("{id}", &id.to_string());
+                Cow::Owned(name)
+            };
+            let at = usize::from(*id) * 12;
+            let (offset, _) = table_offset(table, usize::from(*id));
+            let rom_size = u32::from(u16::from_be_bytes([table[at + 6], table[at + 7]])) * 4;
+            let boundary = |name, place| OutputSymbol {
+                name,
+                place,
+                size: 0,
+                kind: SymKind::Unknown,
+                scope: SymScope::Global,
+                weak: false,
+            };
+
+            per_file.push((*id, boundary(name(start), Placement::Files(offset))));
+            per_file.push((
+                *id,
+                boundary(name(end), Placement::Files(offset + rom_size)),
+            ));
+            per_file.push((
+                *id,
+                OutputSymbol {
+                    name: name(entry),
+                    place: Placement::Table(at as u32),
+                    size: 12,
+                    kind: SymKind::Data,
+                    scope: SymScope::Global,
+                    weak: false,
+                },
+            ));
+        }
+    }
+
+    Ok(per_file)
 }
 
-fn create_object(p2: pass2::Pass2) -> write::Object<'static> {
+/// Report per file symbols that name the same symbol for two files (like `a.bin` and
+/// `a.o`), or that are already exported
+fn check_file_symbols(out: &LinkOutput) -> Result<()> {
+    let path = |id: usize| out.inputs[id].clone().unwrap_or_default();
+
+    let mut defined = out
+        .symbols
+        .iter()
+        .map(|(name, sym)| (name.clone(), sym.file))
+        .collect::<HashMap<_, _>>();
+    let mut duplicates = Diagnostics::default();
+    for (id, sym) in file_symbols(out)? {
+        match defined.entry(sym.name.into_owned()) {
+            Entry::Occupied(first) => duplicates.push(Diagnostic::DuplicateSymbol {
+                symbol: first.key().clone(),
+                first: path(*first.get()),
+                redefined: path(usize::from(id)),
+            }),
+            Entry::Vacant(v) => {
+                v.insert(usize::from(id));
+            }
+        }
+    }
+
+    Ok(duplicates.into_result()?)
+}
+
+/// Get a per file symbol template, which has to name each file differently
+fn file_symbol_template<'a>(template: &'a Option<String>, default: &'a str) -> Result<&'a str> {
+    let template = template.as_deref().unwrap_or(default);
+    if !template.contains("{name}") && !template.contains("{id}") {
+        bail!(
+            "file symbol template \"{}\" needs a {{name}} or {{id}}",
+            template
+        );
+    }
+
+    Ok(template)
+}
+
+fn create_object(out: &LinkOutput) -> Result<write::Object<'_>> {
     let mut obj = write::Object::new(BinaryFormat::Elf, Architecture::Mips, Endianness::Big);
 
     // set mips2
@@ -101,36 +545,86 @@ fn create_object(p2: pass2::Pass2) -> write::Object<'static> {
     };
 
     let data_seg = obj.segment_name(StandardSegment::Data);
-    let pass2::Pass2 {
+    let LinkOutput {
         table,
         data,
-        symbols,
+        object: settings,
         ..
-    } = p2;
-    let tsec = obj.add_section(data_seg.to_vec(), b".filetable".to_vec(), SectionKind::Data);
-    let fsec = obj.add_section(data_seg.to_vec(), b".files".to_vec(), SectionKind::Data);
+    } = out;
+    let sections = OutputSections::new(settings)?;
 
-    obj.set_section_data(tsec, table, 4);
-    obj.set_section_data(fsec, data, 4);
+    let tsec = obj.add_section(data_seg.to_vec(), sections.table.into(), SectionKind::Data);
+    let fsec = obj.add_section(data_seg.to_vec(), sections.files.into(), SectionKind::Data);
 
-    let create_symbol = |info| create_symbol(fsec, info);
+    // when relocatable, the offset in each entry (and the terminator) is the implicit
+    // addend. the compressed flag stays in it, as the file data is placed in ROM or
+    // segment space, where adding its address can't carry into the flag
+    obj.set_section_data(tsec, table.as_slice(), sections.table_align);
+    obj.set_section_data(fsec, data.as_slice(), sections.files_align);
 
-    for symbol in symbols.into_iter().map(create_symbol) {
-        obj.add_symbol(symbol);
+    if settings.relocatable.unwrap_or(false) {
+        let files = obj.section_symbol(fsec);
+        for offset in (0..table.len()).step_by(12) {
+            let reloc = write::Relocation {
+                offset: offset as u64,
+                size: 32,
+                kind: RelocationKind::Absolute,
+                encoding: RelocationEncoding::Generic,
+                symbol: files,
+                addend: 0,
+            };
+            obj.add_relocation(tsec, reloc)
+                .map_err(|e| anyhow!("relocating file table: {}", e))?;
+        }
     }
 
-    obj
+    for sym in output_symbols(out)? {
+        let (section, value) = match sym.place {
+            Placement::Table(v) => (write::SymbolSection::Section(tsec), v),
+            Placement::Files(v) => (write::SymbolSection::Section(fsec), v),
+            Placement::Absolute(v) => (write::SymbolSection::Absolute, v),
+        };
+        obj.add_symbol(create_symbol(&sym, section, value));
+    }
+
+    Ok(obj)
+}
+
+/// Check that a section alignment is a power of two, or default to a word
+fn section_align(align: Option<u64>) -> Result<u64> {
+    match align {
+        None => Ok(4),
+        Some(a) if a.is_power_of_two() => Ok(a),
+        Some(a) => bail!("{} is not a power of two", a),
+    }
+}
+
+/// The offset and compressed flag in the table entry for file `id`
+fn table_offset(table: &[u8], id: usize) -> (u32, bool) {
+    let at = id * 12;
+    let word = u32::from_be_bytes(table[at..at + 4].try_into().unwrap());
+
+    (word & 0x7FFF_FFFF, word & 0x8000_0000 != 0)
 }
 
-fn create_symbol(sec: SectionId, (name, sym): (String, Sym)) -> write::Symbol {
+fn create_symbol(sym: &OutputSymbol, section: write::SymbolSection, value: u32) -> write::Symbol {
     write::Symbol {
-        name: name.into_bytes(),
-        value: sym.addr as u64,
-        size: 4,
-        kind: object::SymbolKind::Data,
-        scope: object::SymbolScope::Dynamic,
-        weak: false,
-        section: write::SymbolSection::Section(sec),
+        name: sym.name.as_bytes().to_vec(),
+        value: value as u64,
+        size: sym.size as u64,
+        kind: match sym.kind {
+            // object only writes an untyped defined symbol as a label
+            SymKind::Unknown => object::SymbolKind::Label,
+            SymKind::Data => object::SymbolKind::Data,
+            SymKind::Text => object::SymbolKind::Text,
+        },
+        scope: match sym.scope {
+            SymScope::Local => object::SymbolScope::Compilation,
+            SymScope::Hidden => object::SymbolScope::Linkage,
+            SymScope::Global => object::SymbolScope::Dynamic,
+        },
+        weak: sym.weak,
+        section,
         flags: object::SymbolFlags::None,
     }
 }
@@ -153,7 +647,7 @@ fn fmt_as_cident(p: &Path) -> String {
         for cmpt in parent.components() {
             s += "_";
             match cmpt {
-                Component::Normal(p) => s += &valid_c_ident(&*p.to_string_lossy()),
+                Component::Normal(p) => s += &valid_c_ident(&p.to_string_lossy()),
                 Component::Prefix(_)
                 | Component::RootDir
                 | Component::CurDir
@@ -164,7 +658,7 @@ fn fmt_as_cident(p: &Path) -> String {
 
     if let Some(stem) = p.file_stem() {
         s += "_";
-        s += &valid_c_ident(&*stem.to_string_lossy());
+        s += &valid_c_ident(&stem.to_string_lossy());
     }
 
     s
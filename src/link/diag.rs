@@ -0,0 +1,140 @@
+use std::{error::Error, fmt, path::PathBuf};
+
+use serde::Serialize;
+
+/// A problem with the script or its inputs that is reported with every other problem
+/// in the link, instead of stopping at the first one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Diagnostic {
+    /// A symbol is defined by more than one file, or twice by the same file
+    #[serde(rename_all = "camelCase")]
+    DuplicateSymbol {
+        symbol: String,
+        /// the file with the definition that is kept
+        first: PathBuf,
+        redefined: PathBuf,
+    },
+    /// A relocation refers to a symbol that can't be found
+    #[serde(rename_all = "camelCase")]
+    UndefinedSymbol {
+        symbol: String,
+        file: PathBuf,
+        /// the section of an ELF object with the relocation
+        section: Option<String>,
+        /// offset of the relocation in `section`, or in a raw file
+        offset: u64,
+        /// the relocation type of an ELF object
+        relocation: Option<String>,
+        reason: String,
+    },
+    /// An alias in a file's `exports` refers to a symbol that can't be found
+    #[serde(rename_all = "camelCase")]
+    UndefinedAlias {
+        alias: String,
+        alias_of: String,
+        file: PathBuf,
+        reason: String,
+    },
+    /// An entry in a file's `imports` doesn't refer to a file
+    #[serde(rename_all = "camelCase")]
+    UnresolvedImport {
+        import: String,
+        file: PathBuf,
+        reason: String,
+    },
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DuplicateSymbol {
+                symbol,
+                first,
+                redefined,
+            } => write!(
+                f,
+                "Symbol < {} > already defined in file < {} > and redefined in < {} >",
+                symbol,
+                first.display(),
+                redefined.display()
+            ),
+            Self::UndefinedSymbol {
+                symbol,
+                file,
+                section,
+                offset,
+                relocation,
+                reason,
+            } => {
+                write!(f, "< {} >: ", file.display())?;
+                if let Some(section) = section {
+                    write!(f, "{}+", section)?;
+                }
+                write!(f, "{:#x}: ", offset)?;
+                if let Some(relocation) = relocation {
+                    write!(f, "{} ", relocation)?;
+                }
+                write!(f, "against undefined symbol < {} >: {}", symbol, reason)
+            }
+            Self::UndefinedAlias {
+                alias,
+                alias_of,
+                file,
+                reason,
+            } => write!(
+                f,
+                "< {} >: alias < {} > of undefined symbol < {} >: {}",
+                file.display(),
+                alias,
+                alias_of,
+                reason
+            ),
+            Self::UnresolvedImport { file, reason, .. } => {
+                write!(f, "< {} >: unresolved import: {}", file.display(), reason)
+            }
+        }
+    }
+}
+
+/// Every [`Diagnostic`] found in a link. This is the error returned by
+/// [`Linker::link`](crate::Linker::link) when there are any.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl Diagnostics {
+    pub(super) fn push(&mut self, diagnostic: Diagnostic) {
+        self.0.push(diagnostic);
+    }
+
+    pub(super) fn extend(&mut self, other: Diagnostics) {
+        self.0.extend(other.0);
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Return `Err(self)` if there are any diagnostics
+    pub(super) fn into_result(self) -> Result<(), Self> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} problems found while linking:", self.0.len())?;
+        for d in &self.0 {
+            write!(f, "\n    {}", d)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for Diagnostics {}
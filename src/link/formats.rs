@@ -0,0 +1,167 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::link::{output_symbols, LinkOutput, OutputSections, Placement};
+use crate::{SymKind, SymScope};
+
+use anyhow::Result;
+
+/// Write the file table, then the file data at the next multiple of the data alignment
+pub(super) fn write_bin(wtr: &mut impl Write, out: &LinkOutput) -> Result<()> {
+    let sections = OutputSections::new(&out.object)?;
+    let files_at = out
+        .table
+        .len()
+        .next_multiple_of(sections.files_align as usize);
+
+    wtr.write_all(&out.table)?;
+    wtr.write_all(&vec![0; files_at - out.table.len()])?;
+    wtr.write_all(&out.data)?;
+
+    Ok(())
+}
+
+/// Write a GNU as source file with the file table as `.word`s, and an `.incbin` of
+/// the file data at `data_path`
+pub(super) fn write_asm(wtr: &mut impl Write, out: &LinkOutput, data_path: &Path) -> Result<()> {
+    const TABLE: &str = ".Lhalld_table";
+    const FILES: &str = ".Lhalld_files";
+
+    let sections = OutputSections::new(&out.object)?;
+    let relocatable = out.object.relocatable.unwrap_or(false);
+
+    writeln!(wtr, "/* generated by halld */\n")?;
+    write_asm_section(wtr, sections.table, sections.table_align, TABLE)?;
+    for entry in out.table.chunks_exact(12) {
+        let word = |at: usize| u32::from_be_bytes(entry[at..at + 4].try_into().unwrap());
+        let offset = if relocatable {
+            // like the object, the compressed flag is part of the addend
+            format!("{} + {:#010x}", FILES, word(0))
+        } else {
+            format!("{:#010x}", word(0))
+        };
+        writeln!(
+            wtr,
+            "    .word {}, {:#010x}, {:#010x}",
+            offset,
+            word(4),
+            word(8)
+        )?;
+    }
+
+    writeln!(wtr)?;
+    write_asm_section(wtr, sections.files, sections.files_align, FILES)?;
+    writeln!(wtr, "    .incbin \"{}\"", data_path.display())?;
+
+    let mut symbols = output_symbols(out)?;
+    symbols.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    if !symbols.is_empty() {
+        writeln!(wtr)?;
+    }
+    for sym in symbols {
+        let name = &sym.name;
+        match sym.place {
+            Placement::Table(v) => writeln!(wtr, ".set {}, {} + {:#x}", name, TABLE, v)?,
+            Placement::Files(v) => writeln!(wtr, ".set {}, {} + {:#x}", name, FILES, v)?,
+            Placement::Absolute(v) => writeln!(wtr, ".set {}, {:#x}", name, v)?,
+        }
+        match sym.kind {
+            SymKind::Unknown => (),
+            SymKind::Data => writeln!(wtr, ".type {}, @object", name)?,
+            SymKind::Text => writeln!(wtr, ".type {}, @function", name)?,
+        }
+        if sym.size != 0 {
+            writeln!(wtr, ".size {}, {}", name, sym.size)?;
+        }
+        let binding = if sym.weak { ".weak" } else { ".globl" };
+        match sym.scope {
+            SymScope::Local => (),
+            SymScope::Hidden => writeln!(wtr, "{} {}\n.hidden {}", binding, name, name)?,
+            SymScope::Global => writeln!(wtr, "{} {}", binding, name)?,
+        }
+    }
+
+    Ok(())
+}
+
+fn write_asm_section(wtr: &mut impl Write, name: &str, align: u64, label: &str) -> io::Result<()> {
+    writeln!(wtr, ".section {}, \"aw\", @progbits", name)?;
+    writeln!(wtr, ".balign {}", align)?;
+    writeln!(wtr, "{}:", label)
+}
+
+/// Write the file table and file data as C byte arrays, placed in their sections
+pub(super) fn write_c(wtr: &mut impl Write, out: &LinkOutput) -> Result<()> {
+    let sections = OutputSections::new(&out.object)?;
+    let names = out.object.boundary_symbols.as_ref();
+    let table_name = names.and_then(|n| n.table_start.clone());
+    let files_name = names.and_then(|n| n.files_start.clone());
+
+    writeln!(wtr, "/* generated by halld */\n")?;
+    write_c_array(
+        wtr,
+        &table_name.unwrap_or_else(|| c_ident(sections.table)),
+        sections.table,
+        sections.table_align,
+        &out.table,
+    )?;
+    writeln!(wtr)?;
+    write_c_array(
+        wtr,
+        &files_name.unwrap_or_else(|| c_ident(sections.files)),
+        sections.files,
+        sections.files_align,
+        &out.data,
+    )?;
+
+    if let Some(count) = names.and_then(|n| n.file_count.as_deref()) {
+        writeln!(
+            wtr,
+            "\nconst unsigned int {} = {};",
+            count,
+            out.table.len() / 12 - 1
+        )?;
+    }
+
+    Ok(())
+}
+
+fn write_c_array(
+    wtr: &mut impl Write,
+    name: &str,
+    section: &str,
+    align: u64,
+    bytes: &[u8],
+) -> io::Result<()> {
+    writeln!(
+        wtr,
+        "const unsigned char {}[{}] __attribute__((section(\"{}\"), aligned({}))) = {{",
+        name,
+        bytes.len(),
+        section,
+        align
+    )?;
+
+    let mut line = String::with_capacity(16 * 6);
+    for row in bytes.chunks(16) {
+        line.clear();
+        for b in row {
+            write!(&mut line, " {:#04x},", b).unwrap();
+        }
+        writeln!(wtr, "   {}", line)?;
+    }
+
+    writeln!(wtr, "}};")
+}
+
+/// Make a C identifier from a section name, like `.files` to `files`
+fn c_ident(section: &str) -> String {
+    section
+        .trim_start_matches('.')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
@@ -0,0 +1,137 @@
+use crate::link::{
+    diag::{Diagnostic, Diagnostics},
+    namespace::Namespaces,
+};
+use crate::{FileRef, LinkerScript};
+
+use std::{collections::HashMap, path::Path};
+
+/// The names that can refer to a file in `imports`: its path in the script and its C define
+#[derive(Debug)]
+pub(super) struct FileNames {
+    names: HashMap<String, Vec<u16>>,
+    ids: Vec<u16>,
+}
+
+impl FileNames {
+    pub(super) fn with_capacity(files: usize) -> Self {
+        Self {
+            names: HashMap::with_capacity(files * 2),
+            ids: Vec::with_capacity(files),
+        }
+    }
+
+    pub(super) fn add(&mut self, path: &Path, cdef: &str, id: u16) {
+        self.ids.push(id);
+        let path = path.to_string_lossy().into_owned();
+        for name in [path, cdef.to_string()] {
+            let ids = self.names.entry(name).or_default();
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+    }
+
+    /// The id of the file with a path or C define of `name`, if there is one,
+    /// or an error if more than one file has that name
+    pub(super) fn file_id(&self, name: &str) -> Option<Result<u16, String>> {
+        self.names.get(name).map(|ids| match ids.as_slice() {
+            &[id] => Ok(id),
+            ids => Err(format!(
+                "\"{}\" is ambiguous; it could be any of files {:?}",
+                name, ids
+            )),
+        })
+    }
+
+    /// The three file names closest to `name` by edit distance
+    pub(super) fn closest(&self, name: &str) -> Vec<&str> {
+        let mut names = self
+            .names
+            .keys()
+            .map(|n| (edit_distance(n, name), n.as_str()))
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+
+        names.into_iter().take(3).map(|(_, n)| n).collect()
+    }
+}
+
+/// Resolve the `imports` of every file in `script`, with file ids `ids`, to file ids.
+/// Imports that can't be resolved are left out, and described in the diagnostics.
+pub(super) fn resolve_imports(
+    script: &LinkerScript,
+    ids: &[u16],
+    symbols: &Namespaces,
+) -> (Vec<Option<Vec<u16>>>, Diagnostics) {
+    let mut unresolved = Diagnostics::default();
+    let imports = script
+        .iter()
+        .zip(ids)
+        .map(|(entry, &id)| {
+            entry.imports.as_ref().map(|refs| {
+                refs.iter()
+                    .filter_map(|r| {
+                        resolve(r, id, symbols)
+                            .map_err(|reason| {
+                                unresolved.push(Diagnostic::UnresolvedImport {
+                                    import: match r {
+                                        FileRef::Id(id) => id.to_string(),
+                                        FileRef::Name(name) => name.clone(),
+                                    },
+                                    file: entry.file.clone(),
+                                    reason,
+                                })
+                            })
+                            .ok()
+                    })
+                    .collect()
+            })
+        })
+        .collect();
+
+    (imports, unresolved)
+}
+
+/// Find a file by its id, its path or C define, or a symbol that it defines
+fn resolve(r: &FileRef, from: u16, symbols: &Namespaces) -> Result<u16, String> {
+    let names = &symbols.names;
+    let name = match r {
+        FileRef::Id(id) if names.ids.contains(id) => return Ok(*id),
+        FileRef::Id(id) => return Err(format!("no file has id {}", id)),
+        FileRef::Name(name) => name,
+    };
+    if name.contains("::") {
+        return symbols.resolve(name, from).map(|sym| sym.file as u16);
+    }
+
+    match names.file_id(name) {
+        Some(id) => id,
+        None => match symbols.resolve(name, from) {
+            Ok(sym) => Ok(sym.file as u16),
+            Err(_) => Err(format!(
+                "no file or symbol named \"{}\"; closest files: {}",
+                name,
+                names.closest(name).join(", ")
+            )),
+        },
+    }
+}
+
+/// Levenshtein distance between `a` and `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let sub = diag + usize::from(ca != cb);
+            diag = row[j + 1];
+            row[j + 1] = sub.min(row[j] + 1).min(diag + 1);
+        }
+    }
+
+    row[b.len()]
+}
@@ -0,0 +1,96 @@
+use anyhow::{bail, Context, Result};
+use object::{read, Object, ObjectSection, ObjectSymbol, SectionIndex, SectionKind};
+
+/// Where each allocatable data section of an object is placed in its linked file.
+///
+/// Sections with data (`.data`, `.rodata`, custom sections, etc.) are placed first in
+/// the order of the object's section headers, followed by `.bss`-style sections,
+/// which are zero filled. Every section starts on at least a word boundary.
+#[derive(Debug)]
+pub(super) struct SectionLayout {
+    sections: Vec<PlacedSection>,
+    size: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(super) struct PlacedSection {
+    pub(super) index: SectionIndex,
+    pub(super) offset: u32,
+    pub(super) zero_fill: bool,
+}
+
+impl SectionLayout {
+    pub(super) fn new(obj: &read::File) -> Result<Self> {
+        let (filled, zeroed): (Vec<_>, Vec<_>) = obj
+            .sections()
+            .filter(|s| {
+                matches!(
+                    s.kind(),
+                    SectionKind::Data
+                        | SectionKind::ReadOnlyData
+                        | SectionKind::ReadOnlyString
+                        | SectionKind::UninitializedData
+                )
+            })
+            .partition(|s| s.kind() != SectionKind::UninitializedData);
+
+        if filled.is_empty() && zeroed.is_empty() {
+            bail!("no allocatable data sections");
+        }
+
+        let mut sections = Vec::with_capacity(filled.len() + zeroed.len());
+        let mut size = 0u64;
+        for sec in filled.iter().chain(zeroed.iter()) {
+            let align = sec.align().max(4);
+            let offset = size.div_ceil(align) * align;
+            size = offset + sec.size();
+
+            sections.push(PlacedSection {
+                index: sec.index(),
+                offset: u32::try_from(offset)
+                    .with_context(|| format!("placing section {}", sec.name().unwrap_or("?")))?,
+                zero_fill: sec.kind() == SectionKind::UninitializedData,
+            });
+        }
+        let size = u32::try_from(size).context("total size of data sections")?;
+
+        Ok(Self { sections, size })
+    }
+
+    pub(super) fn sections(&self) -> &[PlacedSection] {
+        &self.sections
+    }
+
+    /// The offset of section `index` in the linked file, if it is an allocatable data section
+    pub(super) fn offset_of(&self, index: SectionIndex) -> Option<u32> {
+        self.sections
+            .iter()
+            .find(|s| s.index == index)
+            .map(|s| s.offset)
+    }
+
+    /// The offset of `sym` in the linked file, if it is defined in an allocatable data section
+    pub(super) fn symbol_addr<'data>(&self, sym: &impl ObjectSymbol<'data>) -> Option<u32> {
+        sym.section_index()
+            .and_then(|idx| self.offset_of(idx))
+            .map(|base| base + sym.address() as u32)
+    }
+
+    /// Combine the data of every section into one file, zero filling `.bss`-style sections
+    pub(super) fn build_image(&self, obj: &read::File) -> Result<Vec<u8>> {
+        let mut image = vec![0; self.size as usize];
+
+        for placed in self.sections.iter().filter(|s| !s.zero_fill) {
+            let sec = obj
+                .section_by_index(placed.index)
+                .context("getting placed section")?;
+            let data = sec
+                .data()
+                .with_context(|| format!("reading {} binary", sec.name().unwrap_or("?")))?;
+            let start = placed.offset as usize;
+            image[start..start + data.len()].copy_from_slice(data);
+        }
+
+        Ok(image)
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use crate::link::LinkOutput;
+use crate::{SymKind, SymScope};
+
+use serde::Serialize;
+
+/// A linked file, as described in the link map
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct MapFile<'a> {
+    id: usize,
+    /// `None` for an empty entry that fills a gap in the file ids
+    cident: Option<&'a str>,
+    path: Option<&'a PathBuf>,
+    offset: u32,
+    compressed: bool,
+    size: u32,
+    rom_size: u32,
+    /// `rom_size / size`
+    ratio: f64,
+    inreloc: Option<u32>,
+    exreloc: Option<u32>,
+    externs: Vec<u16>,
+    symbols: Vec<MapSymbol<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct MapSymbol<'a> {
+    name: &'a str,
+    /// offset from the start of the file
+    addr: u32,
+    size: u32,
+    kind: SymKind,
+    scope: SymScope,
+    weak: bool,
+}
+
+/// Describe every file in `out`, in id order
+pub(super) fn map_files(out: &LinkOutput) -> Vec<MapFile<'_>> {
+    let LinkOutput {
+        table,
+        data,
+        symbols,
+        local_symbols,
+        file_ids,
+        inputs,
+        ..
+    } = out;
+
+    let word = |at: usize| u32::from_be_bytes(table[at..at + 4].try_into().unwrap());
+    let half = |at: usize| u16::from_be_bytes([table[at], table[at + 1]]);
+    let chain = |head: u16| (head != 0xFFFF).then_some(u32::from(head) * 4);
+
+    // the last entry only terminates the table
+    (0..table.len() / 12 - 1)
+        .map(|id| {
+            let at = id * 12;
+            let offset = word(at) & 0x7FFF_FFFF;
+            let rom_size = u32::from(half(at + 6)) * 4;
+            let size = u32::from(half(at + 10)) * 4;
+            let next = word(at + 12) & 0x7FFF_FFFF;
+
+            let externs = data[(offset + rom_size) as usize..next as usize]
+                .chunks_exact(2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .collect();
+            let locals = local_symbols.iter().map(|(name, sym)| (name, sym));
+            let mut symbols = symbols
+                .iter()
+                .chain(locals)
+                .filter(|(_, sym)| sym.file == id)
+                .map(|(name, sym)| MapSymbol {
+                    name,
+                    addr: sym.addr,
+                    size: sym.size,
+                    kind: sym.kind,
+                    scope: sym.scope,
+                    weak: sym.weak,
+                })
+                .collect::<Vec<_>>();
+            symbols.sort_unstable_by_key(|s| (s.addr, s.name));
+
+            MapFile {
+                id,
+                cident: file_ids
+                    .iter()
+                    .find(|&&(_, fid)| usize::from(fid) == id)
+                    .map(|(name, _)| name.as_str()),
+                path: inputs[id].as_ref(),
+                offset,
+                compressed: word(at) & 0x8000_0000 != 0,
+                size,
+                rom_size,
+                ratio: if size == 0 {
+                    1.0
+                } else {
+                    f64::from(rom_size) / f64::from(size)
+                },
+                inreloc: chain(half(at + 4)),
+                exreloc: chain(half(at + 8)),
+                externs,
+                symbols,
+            }
+        })
+        .collect()
+}
+
+pub(super) fn write_map(wtr: &mut impl Write, files: &[MapFile]) -> io::Result<()> {
+    let fmt_chain =
+        |head: Option<u32>| head.map_or_else(|| "none".to_string(), |h| format!("{:#x}", h));
+
+    writeln!(
+        wtr,
+        "{:>5}  {:>10}  {:>8}  {:>8}  {:>6}  {:>8}  {:>8}  file",
+        "id", "offset", "size", "rom size", "ratio", "inreloc", "exreloc"
+    )?;
+    for f in files {
+        let (cident, path) = match (f.cident, f.path) {
+            (Some(cident), Some(path)) => (cident, path.display().to_string()),
+            _ => {
+                writeln!(wtr, "{:>5}  {:#010x}  (empty)", f.id, f.offset)?;
+                continue;
+            }
+        };
+
+        writeln!(
+            wtr,
+            "{:>5}  {:#010x}  {:#8x}  {:#8x}  {:>5.1}%  {:>8}  {:>8}  {} < {} >{}",
+            f.id,
+            f.offset,
+            f.size,
+            f.rom_size,
+            f.ratio * 100.0,
+            fmt_chain(f.inreloc),
+            fmt_chain(f.exreloc),
+            cident,
+            path,
+            if f.compressed { " (compressed)" } else { "" },
+        )?;
+        if !f.externs.is_empty() {
+            writeln!(wtr, "{:7}externs: {:?}", "", f.externs)?;
+        }
+        for sym in &f.symbols {
+            writeln!(
+                wtr,
+                "{:7}{:#010x}  {:#6x}  {:<7}  {:<6}  {}{}",
+                "",
+                sym.addr,
+                sym.size,
+                format!("{:?}", sym.kind).to_lowercase(),
+                format!("{:?}", sym.scope).to_lowercase(),
+                sym.name,
+                if sym.weak { " (weak)" } else { "" },
+            )?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use anyhow::{anyhow, Result};
+use object::{elf, read::Relocation, RelocationKind};
+
+/// Try to resolve the non-pointer relocation `reloc` at `loc` in `data`.
+/// HAL's loader can only relocate 32-bit pointers, so other relocations are only
+/// possible if their value doesn't depend on where the file is loaded.
+///
+/// `absolute` is the value of the target symbol if it is an absolute symbol, and
+/// `paired_lo` is the location of the `R_MIPS_LO16` that follows an `R_MIPS_HI16`.
+/// Returns `false` if the relocation can't be resolved before the file is loaded.
+pub(super) fn resolve(
+    data: &mut [u8],
+    loc: usize,
+    reloc: &Relocation,
+    absolute: Option<u32>,
+    paired_lo: Option<usize>,
+) -> Result<bool> {
+    let r_type = match reloc.kind() {
+        RelocationKind::Elf(r_type) => r_type,
+        _ => return Ok(false),
+    };
+    let explicit = (!reloc.has_implicit_addend()).then(|| reloc.addend() as u32);
+
+    match (r_type, absolute) {
+        (elf::R_MIPS_HI16, Some(sym)) => {
+            let addend = match explicit {
+                Some(a) => a,
+                None => {
+                    let lo = paired_lo
+                        .ok_or_else(|| anyhow!("R_MIPS_HI16 at {:#x} has no R_MIPS_LO16", loc))?;
+                    (u32::from(read_imm(data, loc)?) << 16)
+                        .wrapping_add(read_imm(data, lo)? as i16 as u32)
+                }
+            };
+            // %hi is rounded up when %lo will be negative
+            let value = sym.wrapping_add(addend).wrapping_add(0x8000) >> 16;
+            write_imm(data, loc, value as u16)?;
+        }
+        (elf::R_MIPS_LO16, Some(sym)) => {
+            let addend = match explicit {
+                Some(a) => a,
+                None => read_imm(data, loc)? as i16 as u32,
+            };
+            write_imm(data, loc, sym.wrapping_add(addend) as u16)?;
+        }
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+/// Name a MIPS relocation type for diagnostics
+pub(super) fn reloc_name(reloc: &Relocation) -> String {
+    let name = match (reloc.kind(), reloc.size()) {
+        (RelocationKind::Absolute, 16) => "R_MIPS_16",
+        (RelocationKind::Absolute, 32) => "R_MIPS_32",
+        (RelocationKind::Absolute, 64) => "R_MIPS_64",
+        (RelocationKind::Elf(r_type), _) => match r_type {
+            elf::R_MIPS_REL32 => "R_MIPS_REL32",
+            elf::R_MIPS_26 => "R_MIPS_26",
+            elf::R_MIPS_HI16 => "R_MIPS_HI16",
+            elf::R_MIPS_LO16 => "R_MIPS_LO16",
+            elf::R_MIPS_GPREL16 => "R_MIPS_GPREL16",
+            elf::R_MIPS_LITERAL => "R_MIPS_LITERAL",
+            elf::R_MIPS_GOT16 => "R_MIPS_GOT16",
+            elf::R_MIPS_PC16 => "R_MIPS_PC16",
+            elf::R_MIPS_CALL16 => "R_MIPS_CALL16",
+            elf::R_MIPS_GPREL32 => "R_MIPS_GPREL32",
+            _ => return format!("R_MIPS type {}", r_type),
+        },
+        (kind, size) => return format!("{:?} ({} bits)", kind, size),
+    };
+
+    name.to_string()
+}
+
+/// Read the 16 bit immediate of the instruction at `loc`
+fn read_imm(data: &[u8], loc: usize) -> Result<u16> {
+    data.get(loc + 2..loc + 4)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| anyhow!("instruction at {:#x} is outside of file data", loc))
+}
+
+/// Write the 16 bit immediate of the instruction at `loc`
+fn write_imm(data: &mut [u8], loc: usize, imm: u16) -> Result<()> {
+    data.get_mut(loc + 2..loc + 4)
+        .map(|b| b.copy_from_slice(&imm.to_be_bytes()))
+        .ok_or_else(|| anyhow!("instruction at {:#x} is outside of file data", loc))
+}
@@ -0,0 +1,96 @@
+use crate::link::{imports::FileNames, Sym, SymMap};
+use crate::SymScope;
+
+use std::collections::HashMap;
+
+/// Every file's symbols, and the exported symbols that every file can see.
+///
+/// Any symbol of a file can be referred to as `file::symbol`, where `file` is the file's
+/// path in the script or its C define. Only exported symbols can be referred to by name alone.
+#[derive(Debug)]
+pub(super) struct Namespaces {
+    pub(super) names: FileNames,
+    global: SymMap,
+    files: HashMap<u16, SymMap>,
+}
+
+impl Namespaces {
+    pub(super) fn with_capacity(files: usize) -> Self {
+        Self {
+            names: FileNames::with_capacity(files),
+            global: SymMap::with_capacity(files),
+            files: HashMap::with_capacity(files),
+        }
+    }
+
+    /// Add a symbol to its file's namespace, and to the global namespace unless it is
+    /// local. A non-weak symbol replaces a weak one, and a weak symbol never replaces
+    /// another symbol. Returns the earlier symbol if two non-weak symbols have the same name.
+    pub(super) fn insert(&mut self, name: String, sym: Sym) -> Option<Sym> {
+        self.insert_in(sym.file as u16, name, sym)
+    }
+
+    /// Like [`Namespaces::insert`], but into the namespace of file `file`, which is
+    /// where an alias of another file's symbol is declared
+    pub(super) fn insert_in(&mut self, file: u16, name: String, sym: Sym) -> Option<Sym> {
+        let file = self.files.entry(file).or_default();
+        let global = (sym.scope != SymScope::Local).then_some(&mut self.global);
+
+        let mut maps = Some(file).into_iter().chain(global).collect::<Vec<_>>();
+        for map in &maps {
+            match map.get(&name) {
+                Some(old) if !old.weak && !sym.weak => return Some(*old),
+                _ => (),
+            }
+        }
+        for map in &mut maps {
+            let replaces = map.get(&name).is_none_or(|old| old.weak && !sym.weak);
+            if replaces {
+                map.insert(name.clone(), sym);
+            }
+        }
+
+        None
+    }
+
+    /// Find the symbol that file `from` refers to as `name`: either `file::symbol`,
+    /// or a non-weak symbol in `from`'s namespace, then in the global namespace
+    pub(super) fn resolve(&self, name: &str, from: u16) -> Result<&Sym, String> {
+        let Some((file, symbol)) = name.rsplit_once("::") else {
+            // a weak symbol in the file is replaced by an exported non-weak one
+            let own = self.files.get(&from).and_then(|ns| ns.get(name));
+            return own
+                .filter(|sym| !sym.weak)
+                .or_else(|| self.global.get(name))
+                .or(own)
+                .ok_or_else(|| format!("no exported symbol named \"{}\"", name));
+        };
+
+        let id = self.names.file_id(file).unwrap_or_else(|| {
+            Err(format!(
+                "no file named \"{}\"; closest files: {}",
+                file,
+                self.names.closest(file).join(", ")
+            ))
+        })?;
+        self.files
+            .get(&id)
+            .and_then(|ns| ns.get(symbol))
+            .ok_or_else(|| format!("\"{}\" has no symbol named \"{}\"", file, symbol))
+    }
+
+    /// The global symbols, and the local symbols that are only in their file's
+    /// namespace sorted by file and address
+    pub(super) fn into_symbols(self) -> (SymMap, Vec<(String, Sym)>) {
+        let mut locals = self
+            .files
+            .into_values()
+            .flatten()
+            .filter(|(_, sym)| sym.scope == SymScope::Local)
+            .collect::<Vec<_>>();
+        locals
+            .sort_unstable_by(|(a, sa), (b, sb)| (sa.file, sa.addr, a).cmp(&(sb.file, sb.addr, b)));
+
+        (self.global, locals)
+    }
+}
@@ -1,16 +1,29 @@
-use std::{fs, path::PathBuf};
+use std::{collections::HashMap, fs, path::PathBuf};
 
-use crate::link::{self, CDefs, Sym, SymMap};
-use halld::LinkerScript;
+use crate::link::{
+    self,
+    diag::{Diagnostic, Diagnostics},
+    imports,
+    layout::SectionLayout,
+    namespace::Namespaces,
+    CDefs, Sym,
+};
+use crate::{Export, FileType, InputFile, LinkerScript, SymKind, SymScope};
 
 use anyhow::{bail, Context, Result};
-use object::{read, Object, ObjectSymbol, SymbolKind, SymbolSection};
+use object::{read, Object, ObjectSymbol, SymbolKind, SymbolScope};
 
 #[derive(Debug)]
 pub(super) struct Pass1 {
     pub(super) script: LinkerScript,
-    pub(super) sym_map: SymMap,
+    pub(super) symbols: Namespaces,
     pub(super) c_header: CDefs,
+    /// The file id of each file in `script`
+    pub(super) ids: Vec<u16>,
+    /// The file ids of each file's `imports`
+    pub(super) imports: Vec<Option<Vec<u16>>>,
+    /// Duplicate symbols and unresolved imports
+    pub(super) diagnostics: Diagnostics,
 }
 
 impl Pass1 {
@@ -25,72 +38,265 @@ impl Pass1 {
             );
         }
 
-        let mut sym_map = SymMap::with_capacity(script.len());
+        let ids = assign_ids(&script)?;
+        let mut symbols = Namespaces::with_capacity(script.len());
         let mut c_header = Vec::with_capacity(script.len());
-        let mut sym_clash = None;
-        for (i, entry) in script.iter_mut().enumerate() {
+        let mut clashes = Vec::new();
+        let mut aliases = Vec::new();
+        for (i, (entry, &idx)) in script.iter_mut().zip(&ids).enumerate() {
             // use the original name for creating c defines
-            let idx = i as u16;
             let def = (link::fmt_as_cident(&entry.file), idx);
+            symbols.names.add(&entry.file, &def.0, idx);
             c_header.push(def);
             // what to do about the same named files...?
             locate_file(&mut entry.file, search).context("locating files to link")?;
+            entry.file_type = Some(link::detect_file_type(entry)?);
+            if entry.relocs.is_some() {
+                check_raw_relocs(entry)?;
+            }
+            if let Some(relocs) = entry.relocs.as_mut() {
+                locate_file(relocs, search).context("locating relocation files")?;
+            }
 
-            if link::is_object(&entry.file) {
-                let file = fs::read(&entry.file)?;
-                let obj = read::File::parse(&*file)?;
-                for sym in obj.symbols() {
-                    //println!("{:#?}", sym);
-                    // todo: check that the symbol is in the data section
-                    if sym.kind() == SymbolKind::Unknown
-                        && sym.is_global()
-                        && sym.section() != SymbolSection::Undefined
-                    {
-                        let name = sym.name()?.to_string();
-                        let addr = sym.address() as u32;
-                        sym_clash = sym_map
-                            .insert(name, Sym { addr, file: i })
-                            .map(|old| (sym.name().unwrap().to_string(), old, i));
-                    } else {
-                        //println!("unneeded symbol? {:#?}", sym);
-                    }
-                }
+            let defined = if entry.file_type == Some(FileType::Elf) {
+                elf_symbols(entry, idx)?
             } else if let Some(syms) = entry.exports.as_ref() {
-                for (name, addr) in syms.iter() {
-                    let sym = Sym {
-                        addr: *addr,
-                        file: i,
-                    };
-                    sym_clash = sym_map
-                        .insert(name.clone(), sym)
-                        .map(|old| (name.clone(), old, i));
+                syms.iter()
+                    .filter(|export| !matches!(export, Export::Alias(_)))
+                    .map(|export| export_sym(export, idx))
+                    .collect::<Result<Vec<_>>>()
+                    .with_context(|| format!("exports of < {} >", entry.file.display()))?
+            } else {
+                Vec::new()
+            };
+
+            // the first definition is kept, so the rest of the link can be checked
+            for (name, sym) in defined {
+                if let Some(old) = symbols.insert(name.clone(), sym) {
+                    clashes.push((name, old, i));
                 }
             }
+            let exports = entry.exports.iter().flatten();
+            aliases.extend(exports.filter_map(|export| match export {
+                Export::Alias(alias) => Some((i, idx, alias.clone())),
+                _ => None,
+            }));
+        }
 
-            if sym_clash.is_some() {
+        // an alias can point to another alias, so keep going until none can be resolved
+        loop {
+            let pending = aliases.len();
+            aliases.retain(|(i, idx, alias)| {
+                let target = match symbols.resolve(&alias.alias_of, *idx) {
+                    Ok(&target) => target,
+                    Err(_) => return true,
+                };
+                // relocations use the target's file and address, but the alias is
+                // declared by (and in the namespace of) file `idx`
+                let sym = Sym {
+                    scope: alias.scope.unwrap_or(SymScope::Global),
+                    weak: alias.weak.unwrap_or(false),
+                    ..target
+                };
+                if let Some(old) = symbols.insert_in(*idx, alias.name.clone(), sym) {
+                    clashes.push((alias.name.clone(), old, *i));
+                }
+                false
+            });
+            if aliases.len() == pending {
                 break;
             }
         }
 
-        if let Some((name, sym, next)) = sym_clash {
-            bail!(
-                "Symbol < {} > already definied in file < {} > and redefined in < {} >",
-                name,
-                script[sym.file].file.display(),
-                script[next].file.display()
-            );
+        let (imports, mut diagnostics) = imports::resolve_imports(&script, &ids, &symbols);
+        for (symbol, sym, next) in clashes {
+            let first = ids.iter().position(|&id| id as usize == sym.file).unwrap();
+            diagnostics.push(Diagnostic::DuplicateSymbol {
+                symbol,
+                first: script[first].file.clone(),
+                redefined: script[next].file.clone(),
+            });
+        }
+        for (i, idx, alias) in aliases {
+            diagnostics.push(Diagnostic::UndefinedAlias {
+                reason: symbols.resolve(&alias.alias_of, idx).unwrap_err(),
+                alias: alias.name,
+                alias_of: alias.alias_of,
+                file: script[i].file.clone(),
+            });
         }
+        c_header.sort_unstable_by_key(|&(_, id)| id);
 
         Ok(Self {
             script,
-            sym_map,
+            symbols,
             c_header,
+            ids,
+            imports,
+            diagnostics,
         })
     }
 }
 
+/// The name in an ELF object's `exports` that exports all of its global symbols
+const EXPORT_ALL: &str = "*";
+
+/// The global data and text symbols of an ELF object. Without `exports` in the script,
+/// every one is exported. Otherwise only the symbols it names (or every one, for `"*"`)
+/// are exported; the rest are only in the object's namespace.
+fn elf_symbols(entry: &InputFile, file: u16) -> Result<Vec<(String, Sym)>> {
+    let data = fs::read(&entry.file)?;
+    let obj = read::File::parse(&*data)?;
+    let layout = SectionLayout::new(&obj)
+        .with_context(|| format!("laying out < {} >", entry.file.display()))?;
+
+    let mut syms = Vec::new();
+    for sym in obj.symbols() {
+        // only symbols in the data sections are exported
+        let addr = layout.symbol_addr(&sym);
+        let kind = match sym.kind() {
+            SymbolKind::Unknown => Some(SymKind::Unknown),
+            SymbolKind::Data => Some(SymKind::Data),
+            SymbolKind::Text => Some(SymKind::Text),
+            _ => None,
+        };
+        if let Some((addr, kind)) = addr.zip(kind).filter(|_| sym.is_global()) {
+            let export = Sym {
+                addr,
+                file: file as usize,
+                size: sym.size() as u32,
+                kind,
+                scope: match sym.scope() {
+                    SymbolScope::Linkage => SymScope::Hidden,
+                    _ => SymScope::Global,
+                },
+                weak: sym.is_weak(),
+            };
+            syms.push((sym.name()?.to_string(), export));
+        }
+    }
+
+    // aliases don't export anything by themselves, so `[]` or only aliases export nothing
+    let mut all = entry.exports.is_none();
+    let mut exported = Vec::new();
+    for export in entry.exports.iter().flatten() {
+        let name = match export {
+            Export::Name(name) if name == EXPORT_ALL => {
+                all = true;
+                continue;
+            }
+            Export::Name(name) => name,
+            Export::Alias(_) => continue,
+            _ => bail!(
+                "< {} > is an ELF object, so its exports can only be symbol names or aliases",
+                entry.file.display()
+            ),
+        };
+        if !syms.iter().any(|(n, _)| n == name) {
+            bail!(
+                "< {} > exports < {} >, but doesn't define it",
+                entry.file.display(),
+                name
+            );
+        }
+        exported.push(name);
+    }
+    for (name, sym) in syms.iter_mut() {
+        if !all && !exported.contains(&&*name) {
+            sym.scope = SymScope::Local;
+        }
+    }
+
+    Ok(syms)
+}
+
+/// The name and symbol for a raw file's export
+fn export_sym(export: &Export, file: u16) -> Result<(String, Sym)> {
+    let (name, sym) = match export {
+        Export::Offset(name, addr) => (
+            name,
+            Sym {
+                addr: *addr,
+                file: file as usize,
+                size: 0,
+                kind: SymKind::Data,
+                scope: SymScope::Global,
+                weak: false,
+            },
+        ),
+        Export::Symbol(sym) => (
+            &sym.name,
+            Sym {
+                addr: sym.addr,
+                file: file as usize,
+                size: sym.size.unwrap_or(0),
+                kind: sym.kind.unwrap_or(SymKind::Data),
+                scope: sym.scope.unwrap_or(SymScope::Global),
+                weak: sym.weak.unwrap_or(false),
+            },
+        ),
+        Export::Name(name) => bail!("< {} > needs an offset in the file", name),
+        Export::Alias(alias) => unreachable!("alias < {} > is resolved later", alias.name),
+    };
+
+    Ok((name.clone(), sym))
+}
+
+/// A relocation file replaces the hand written relocation fields of a raw file
+fn check_raw_relocs(entry: &InputFile) -> Result<()> {
+    let file = entry.file.display();
+    if entry.file_type == Some(FileType::Elf) {
+        bail!(
+            "< {} > is an ELF object, so it can't use a relocation file",
+            file
+        );
+    }
+    if entry.inreloc.is_some() || entry.exreloc.is_some() || entry.imports.is_some() {
+        bail!(
+            "< {} > has a relocation file, so it can't also set inreloc, exreloc, or imports",
+            file
+        );
+    }
+
+    Ok(())
+}
+
+/// Give each file its pinned `id`, or the id after the previous file
+fn assign_ids(script: &LinkerScript) -> Result<Vec<u16>> {
+    let mut ids = Vec::with_capacity(script.len());
+    let mut owners = HashMap::with_capacity(script.len());
+    let mut next = 0;
+
+    for (i, entry) in script.iter().enumerate() {
+        let id = match entry.id {
+            Some(id) => id,
+            None => u16::try_from(next).with_context(|| {
+                format!(
+                    "file id for < {} > is past the max of {}",
+                    entry.file.display(),
+                    u16::MAX
+                )
+            })?,
+        };
+        if let Some(prev) = owners.insert(id, i) {
+            let prev = &script[prev];
+            bail!(
+                "File id {} is used by both < {} > and < {} >",
+                id,
+                prev.file.display(),
+                entry.file.display()
+            );
+        }
+
+        ids.push(id);
+        next = u32::from(id) + 1;
+    }
+
+    Ok(ids)
+}
+
 fn locate_file(file: &mut PathBuf, search_dirs: Option<&[PathBuf]>) -> Result<()> {
-    if fs::metadata(&file).map_or(false, |m| m.is_file()) {
+    if fs::metadata(&file).is_ok_and(|m| m.is_file()) {
         return Ok(());
     }
 
@@ -98,7 +304,7 @@ fn locate_file(file: &mut PathBuf, search_dirs: Option<&[PathBuf]>) -> Result<()
         stems
             .iter()
             .map(|s| s.join(file.as_path()))
-            .find(|f| fs::metadata(&f).map_or(false, |m| m.is_file()))
+            .find(|f| fs::metadata(f).is_ok_and(|m| m.is_file()))
     });
 
     if let Some(new_file) = new_file {
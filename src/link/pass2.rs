@@ -1,16 +1,26 @@
 use crate::{
     cache::DataCache,
-    link::{self, pass1::Pass1, CDefs, SymMap},
+    link::{
+        diag::{Diagnostic, Diagnostics},
+        layout::SectionLayout,
+        mips,
+        namespace::Namespaces,
+        pass1::Pass1,
+        CDefs, Sym, SymMap,
+    },
+    FileType, InputFile, RawReloc, RelocTarget, VpkSettings,
 };
 
 use std::{
-    fs,
+    fs::{self, File},
+    io::BufReader,
     path::{Path, PathBuf},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
-use halld::{InputFile, VpkSettings};
-use object::{read, Object, ObjectSection, ObjectSymbol, RelocationTarget};
+use object::{
+    elf, read, Object, ObjectSection, ObjectSymbol, RelocationKind, RelocationTarget, SymbolSection,
+};
 use rayon::prelude::*;
 use vpk0::{format::VpkMethod, Encoder};
 
@@ -20,39 +30,76 @@ pub(super) struct Pass2 {
     pub(super) data: Vec<u8>,
     pub(super) c_header: CDefs,
     pub(super) symbols: SymMap,
-    pub(super) inputs: Vec<PathBuf>,
+    pub(super) local_symbols: Vec<(String, Sym)>,
+    pub(super) inputs: Vec<Option<PathBuf>>,
+    pub(super) reloc_files: Vec<PathBuf>,
+    pub(super) cache_keys: Vec<String>,
 }
 
 impl Pass2 {
-    pub(super) fn run(pass1: Pass1, cache: Option<PathBuf>) -> Result<Self> {
+    pub(super) fn run(pass1: Pass1, vpk_cache: Option<DataCache>) -> Result<Self> {
         let Pass1 {
             script,
-            sym_map,
+            symbols,
             c_header,
+            ids,
+            imports,
+            mut diagnostics,
         } = pass1;
+        // don't spend time compressing if the link is going to fail anyway
+        let check_only = !diagnostics.is_empty();
 
         let mut output = Vec::with_capacity(0x0100_0000);
-        let mut table = Vec::with_capacity((script.len() + 1) * 12);
-        let mut inputs = Vec::with_capacity(script.len());
-        let vpk_cache = cache
-            .map(DataCache::new)
-            .transpose()
-            .context("creating vpk compressiong cache")?;
+        let count = ids.iter().max().map_or(0, |&id| usize::from(id) + 1);
+        let mut table = Vec::with_capacity((count + 1) * 12);
+        let mut inputs = Vec::with_capacity(count);
+        let reloc_files = script.iter().filter_map(|e| e.relocs.clone()).collect();
 
         // This could maybe be done in one pass with a custom IndexedParellelIterator...?
         let processed = script
             .into_par_iter()
-            .map(|entry| process_linked_file(entry, &sym_map, vpk_cache.as_ref()))
-            .collect::<Result<Vec<_>>>()
-            .context("reading and compressing file data in pass2")?;
+            .zip(ids.par_iter())
+            .zip(imports)
+            .map(|((entry, &id), imports)| {
+                let cache = vpk_cache.as_ref();
+                process_linked_file(entry, id, imports, &symbols, cache, check_only)
+            })
+            .collect::<Vec<_>>();
+
+        // gather the undefined symbols from every file before failing
+        let mut files = Vec::with_capacity(processed.len());
+        for res in processed {
+            match res {
+                Ok(file) => files.push(file),
+                Err(e) => match e.downcast_ref::<Diagnostics>() {
+                    Some(found) => diagnostics.extend(found.clone()),
+                    None => return Err(e.context("reading and compressing file data in pass2")),
+                },
+            }
+        }
+        diagnostics.into_result()?;
+        let processed = files;
+        let cache_keys = match vpk_cache {
+            Some(c) => {
+                c.save_run_stats().context("saving cache statistics")?;
+                c.used()
+            }
+            None => Vec::new(),
+        };
 
-        for (_i, res) in processed.into_iter().enumerate() {
+        // ids without a file are filled with empty entries
+        let mut by_id = (0..count).map(|_| None).collect::<Vec<_>>();
+        for (res, id) in processed.into_iter().zip(ids) {
+            by_id[usize::from(id)] = Some(res);
+        }
+
+        for res in by_id {
             let ProcessedFile {
                 path,
                 data,
                 basic,
                 externs,
-            } = res;
+            } = res.unwrap_or_else(ProcessedFile::empty);
 
             let offset = output.len() as u32;
             let info = FileInfo::from((offset, basic));
@@ -67,13 +114,17 @@ impl Pass2 {
         }
 
         terminate_table(&mut table, output.len()).context("terminating resource table")?;
+        let (symbols, local_symbols) = symbols.into_symbols();
 
         Ok(Self {
             table,
             data: output,
             c_header,
-            symbols: sym_map,
+            symbols,
+            local_symbols,
             inputs,
+            reloc_files,
+            cache_keys,
         })
     }
 }
@@ -102,12 +153,30 @@ impl From<(u32, BasicFileInfo)> for FileInfo {
 }
 
 struct ProcessedFile {
-    path: PathBuf,
+    path: Option<PathBuf>,
     data: Vec<u8>,
     basic: BasicFileInfo,
     externs: Option<Vec<u16>>,
 }
 
+impl ProcessedFile {
+    /// A file with no data to fill an unused id
+    fn empty() -> Self {
+        Self {
+            path: None,
+            data: Vec::new(),
+            basic: BasicFileInfo {
+                size: 0,
+                rom_size: 0,
+                compressed: false,
+                inreloc: None,
+                exreloc: None,
+            },
+            externs: None,
+        }
+    }
+}
+
 /// `FileInfo` without the offset (not yet known)
 struct BasicFileInfo {
     size: u32,
@@ -117,49 +186,91 @@ struct BasicFileInfo {
     exreloc: Option<u32>,
 }
 
-fn process_linked_file(
-    entry: InputFile,
-    syms: &SymMap,
-    cache: Option<&DataCache>,
-) -> Result<ProcessedFile> {
-    let InputFile {
-        file,
-        compressed,
-        comp_settings,
-        inreloc,
-        exreloc,
+/// The cache key of every compressed file in the script. Each file is relocated but not
+/// compressed, and problems in other files (or duplicate symbols) are ignored, since they
+/// don't change the data that is compressed.
+pub(super) fn cache_keys(pass1: Pass1) -> Result<Vec<String>> {
+    let Pass1 {
+        script,
+        symbols,
+        ids,
         imports,
         ..
-    } = entry;
-
-    //println!("processing <{}>", file.display());
+    } = pass1;
+
+    script
+        .into_par_iter()
+        .zip(ids.par_iter())
+        .zip(imports)
+        .filter(|((entry, _), _)| entry.compressed)
+        .map(|((entry, &id), imports)| {
+            let settings = entry.comp_settings.clone();
+            let (data, ..) = relocate_file(&entry, id, imports, &symbols)?;
+            Ok(DataCache::key(&data, settings.as_ref()))
+        })
+        .collect()
+}
 
-    let (mut data, externs, inreloc, exreloc) = if link::is_object(&file) {
-        relocate_obj(&file, syms).with_context(|| format!("relocating < {} >", file.display()))?
+/// Read and relocate the data of `entry`, zero aligned to a word
+fn relocate_file(
+    entry: &InputFile,
+    id: u16,
+    imports: Option<Vec<u16>>,
+    syms: &Namespaces,
+) -> Result<RelInfo> {
+    let file = &entry.file;
+    let (mut data, externs, inreloc, exreloc) = if entry.file_type == Some(FileType::Elf) {
+        relocate_obj(file, id, syms)
+            .with_context(|| format!("relocating < {} >", file.display()))?
     } else {
         let data =
-            fs::read(&file).with_context(|| format!("reading < {} > in pass 2", file.display()))?;
+            fs::read(file).with_context(|| format!("reading < {} > in pass 2", file.display()))?;
 
-        (data, imports, inreloc, exreloc)
+        match entry.relocs.as_deref() {
+            Some(relocs) => relocate_raw(data, file, relocs, id, syms)
+                .with_context(|| format!("relocating < {} >", file.display()))?,
+            None => (data, imports, entry.inreloc, entry.exreloc),
+        }
     };
 
     // zero align raw data to word (4byte) size
     align_buffer(&mut data);
+
+    Ok((data, externs, inreloc, exreloc))
+}
+
+fn process_linked_file(
+    entry: InputFile,
+    id: u16,
+    imports: Option<Vec<u16>>,
+    syms: &Namespaces,
+    cache: Option<&DataCache>,
+    check_only: bool,
+) -> Result<ProcessedFile> {
+    //println!("processing <{}>", file.display());
+
+    let (data, externs, inreloc, exreloc) = relocate_file(&entry, id, imports, syms)?;
+    let InputFile {
+        file,
+        compressed,
+        comp_settings,
+        ..
+    } = entry;
     let size = u32::try_from(data.len())?;
 
-    let (data, rom_size) = if compressed {
+    let (data, rom_size) = if compressed && !check_only {
         // store only the compressed vpk0 bytes in the cache
         // add excess and padding after
-        let mut d = if let Some(cached_data) = cache.and_then(|c| c.read(&data)) {
+        let settings = comp_settings.as_ref();
+        let mut d = if let Some(cached_data) = cache.and_then(|c| c.read(&data, settings)) {
             //println!("found in cache: {}", file.display());
             cached_data
         } else {
-            let settings = comp_settings.as_ref();
             let compressed = compress_data(&data, settings)
                 .with_context(|| format!("compressing <{}>", file.display()))?;
 
             if let Some(c) = cache {
-                c.write(&data, &compressed)
+                c.write(&data, settings, &file, &compressed)
                     .with_context(|| format!("caching compressed data for <{}>", file.display()))?;
             }
             compressed
@@ -188,60 +299,202 @@ fn process_linked_file(
         data,
         basic,
         externs,
-        path: file,
+        path: Some(file),
     })
 }
 
 type RelInfo = (Vec<u8>, Option<Vec<u16>>, Option<u32>, Option<u32>);
 
-/// Right now, this only extracts and relocates data from the .data section of an object
-fn relocate_obj(p: &Path, sym_map: &SymMap) -> Result<RelInfo> {
+/// Combine the allocatable data sections of an object into one file, and relocate it
+fn relocate_obj(p: &Path, id: u16, symbols: &Namespaces) -> Result<RelInfo> {
     let file = fs::read(p).context("opening object for relocation")?;
     let obj = read::File::parse(&*file).context("parsing object for relocation")?;
-    let data_sec = obj
-        .section_by_name(".data")
-        .ok_or_else(|| anyhow!("missing .data section"))?;
-
-    // might be able to make this a Cow
-    let mut data = data_sec.data().context("reading .data binary")?.to_vec();
-    let mut externs = Vec::new();
+    let layout = SectionLayout::new(&obj)?;
+    let mut data = layout.build_image(&obj)?;
 
     // separate internal and external relocations
     let mut internal_relocs = Vec::with_capacity(16);
     let mut external_relocs = Vec::with_capacity(16);
-    for (loc, reloc) in data_sec.relocations() {
-        let loc = loc as usize;
-        if reloc.size() != 32 {
-            bail!("can only relocate 32bit pointers; {:?}", reloc);
+    let mut unrepresentable = Vec::new();
+    let mut undefined = Diagnostics::default();
+    for placed in layout.sections() {
+        let sec = obj.section_by_index(placed.index)?;
+        let sec_name = sec.name()?;
+        let relocs = sec.relocations().collect::<Vec<_>>();
+
+        for (i, (offset, reloc)) in relocs.iter().enumerate() {
+            let loc = (placed.offset as u64 + offset) as usize;
+            let sym = match reloc.target() {
+                RelocationTarget::Symbol(idx) => obj.symbol_by_index(idx)?,
+                unk => bail!("unsupported relocation target: {:#?}", unk),
+            };
+            let absolute = (sym.section() == SymbolSection::Absolute).then(|| sym.address() as u32);
+            let local = layout.symbol_addr(&sym);
+
+            let is_pointer = reloc.kind() == RelocationKind::Absolute && reloc.size() == 32;
+            if !is_pointer {
+                let paired_lo = relocs[i + 1..]
+                    .iter()
+                    .find(|(_, r)| {
+                        r.kind() == RelocationKind::Elf(elf::R_MIPS_LO16)
+                            && r.target() == reloc.target()
+                    })
+                    .map(|(o, _)| (placed.offset as u64 + o) as usize);
+                let resolved = mips::resolve(&mut data, loc, reloc, absolute, paired_lo)
+                    .with_context(|| {
+                        format!("resolving relocation at {}+{:#x}", sec_name, offset)
+                    })?;
+
+                if !resolved {
+                    unrepresentable.push(describe_reloc(&obj, sec_name, *offset, reloc, &sym)?);
+                }
+                continue;
+            }
+
+            let addend = if reloc.has_implicit_addend() {
+                let val = data.get(loc..loc + 4).ok_or_else(|| {
+                    anyhow!("relocation at {:#x} is outside of {}", offset, sec_name)
+                })?;
+                u32::from_be_bytes(val.try_into()?)
+            } else {
+                reloc.addend() as u32
+            };
+
+            if let Some(value) = absolute {
+                // doesn't depend on where the file is loaded
+                let value = value.wrapping_add(addend);
+                data[loc..loc + 4].copy_from_slice(&value.to_be_bytes());
+            } else if let Some(addr) = local.filter(|_| !sym.is_weak()) {
+                // internal relocation; gas seems to use section symbols,
+                // but a local or global label in any data section works the same
+                internal_relocs.push((loc, addr.wrapping_add(addend)));
+            } else if sym.is_undefined() || local.is_some() {
+                // a weak symbol defined here can still be replaced by another file's
+                let sym_name = sym.name()?;
+                let ext = match symbols.resolve(sym_name, id) {
+                    Ok(ext) => ext,
+                    Err(reason) => {
+                        undefined.push(Diagnostic::UndefinedSymbol {
+                            symbol: sym_name.to_string(),
+                            file: p.to_path_buf(),
+                            section: Some(sec_name.to_string()),
+                            offset: *offset,
+                            relocation: Some(mips::reloc_name(reloc)),
+                            reason,
+                        });
+                        continue;
+                    }
+                };
+                if ext.file == usize::from(id) {
+                    internal_relocs.push((loc, ext.addr.wrapping_add(addend)));
+                } else {
+                    external_relocs.push((loc, ext.addr.wrapping_add(addend), ext.file as u16));
+                }
+            } else {
+                // a pointer to a section that isn't part of the file, like .text
+                unrepresentable.push(describe_reloc(&obj, sec_name, *offset, reloc, &sym)?);
+            }
         }
-        let sym_name = match reloc.target() {
-            RelocationTarget::Symbol(idx) => obj.symbol_by_index(idx)?.name()?,
-            unk => bail!("unsupported relocation target: {:#?}", unk),
-        };
+    }
 
-        if sym_name == ".data" {
-            // internal relocation? gas seems to set the symbol to the section name
-            let val = &data[loc..loc + 4];
-            let r = (loc, u32::from_be_bytes(val.try_into()?));
-            internal_relocs.push(r);
-        } else {
-            let sym = sym_map.get(sym_name).ok_or_else(|| {
-                anyhow!(
-                    "couldn't find external symbol <{}> for relocation",
-                    sym_name
-                )
-            })?;
-            let val = sym.addr;
-            external_relocs.push((loc, val));
-            externs.push(sym.file as u16);
+    undefined.into_result()?;
+    if !unrepresentable.is_empty() {
+        bail!(
+            "{} relocations can't be represented in HAL's format, which only relocates \
+            32-bit pointers to data:\n    {}",
+            unrepresentable.len(),
+            unrepresentable.join("\n    ")
+        );
+    }
+
+    build_chains(data, internal_relocs, external_relocs)
+}
+
+/// Relocate raw data with the pointers listed in the JSON file at `relocs`
+fn relocate_raw(
+    data: Vec<u8>,
+    file: &Path,
+    relocs: &Path,
+    id: u16,
+    symbols: &Namespaces,
+) -> Result<RelInfo> {
+    let rdr = BufReader::new(
+        File::open(relocs).with_context(|| format!("opening < {} >", relocs.display()))?,
+    );
+    let relocs: Vec<RawReloc> = serde_json::from_reader(rdr)
+        .with_context(|| format!("parsing relocations in < {} >", relocs.display()))?;
+
+    let mut internal_relocs = Vec::with_capacity(relocs.len());
+    let mut external_relocs = Vec::with_capacity(relocs.len());
+    let mut undefined = Diagnostics::default();
+    for RawReloc { at, target } in relocs {
+        let loc = at as usize;
+        match target {
+            RelocTarget::Internal { to } => internal_relocs.push((loc, to)),
+            RelocTarget::Symbol { symbol, addend } => {
+                let sym = match symbols.resolve(&symbol, id) {
+                    Ok(sym) => sym,
+                    Err(reason) => {
+                        undefined.push(Diagnostic::UndefinedSymbol {
+                            symbol,
+                            file: file.to_path_buf(),
+                            section: None,
+                            offset: at.into(),
+                            relocation: None,
+                            reason,
+                        });
+                        continue;
+                    }
+                };
+                if sym.file == usize::from(id) {
+                    internal_relocs.push((loc, sym.addr.wrapping_add_signed(addend)));
+                } else {
+                    external_relocs.push((
+                        loc,
+                        sym.addr.wrapping_add_signed(addend),
+                        sym.file as u16,
+                    ));
+                }
+            }
         }
     }
+    undefined.into_result()?;
+
+    build_chains(data, internal_relocs, external_relocs)
+}
+
+/// Write the internal and external relocation chains into `data`
+fn build_chains(
+    mut data: Vec<u8>,
+    mut internal_relocs: Vec<(usize, u32)>,
+    mut external_relocs: Vec<(usize, u32, u16)>,
+) -> Result<RelInfo> {
+    // keep both chains in ascending order; the extern file ids follow the external chain
+    internal_relocs.sort_unstable_by_key(|&(loc, _)| loc);
+    external_relocs.sort_unstable_by_key(|&(loc, ..)| loc);
+
+    let mut locs = internal_relocs
+        .iter()
+        .map(|&(loc, _)| loc)
+        .chain(external_relocs.iter().map(|&(loc, ..)| loc))
+        .collect::<Vec<_>>();
+    locs.sort_unstable();
+    if let Some(w) = locs.windows(2).find(|w| w[0] == w[1]) {
+        bail!("more than one relocation at {:#x}", w[0]);
+    }
+
+    let externs = external_relocs
+        .iter()
+        .map(|&(.., file)| file)
+        .collect::<Vec<_>>();
+    let external_relocs = external_relocs
+        .into_iter()
+        .map(|(loc, val, _)| (loc, val))
+        .collect::<Vec<_>>();
 
     // apply relocations for each
-    relocate(&mut data, &internal_relocs)
-        .with_context(|| format!("internal relocations in {}", p.display()))?;
-    relocate(&mut data, &internal_relocs)
-        .with_context(|| format!("external relocations in {}", p.display()))?;
+    relocate(&mut data, &internal_relocs).context("internal relocations")?;
+    relocate(&mut data, &external_relocs).context("external relocations")?;
 
     // return external file ids if there were any
     let externs = if externs.is_empty() {
@@ -256,7 +509,33 @@ fn relocate_obj(p: &Path, sym_map: &SymMap) -> Result<RelInfo> {
     Ok((data, externs, inreloc, exreloc))
 }
 
-fn relocate(buf: &mut Vec<u8>, relocations: &[(usize, u32)]) -> Result<()> {
+/// Describe a relocation as `section+offset: type against <symbol>`
+fn describe_reloc(
+    obj: &read::File,
+    sec_name: &str,
+    offset: u64,
+    reloc: &read::Relocation,
+    sym: &read::Symbol,
+) -> Result<String> {
+    let sym_name = match sym.name()? {
+        // section symbols don't have names
+        "" => match sym.section_index() {
+            Some(idx) => obj.section_by_index(idx)?.name()?.to_string(),
+            None => "unnamed symbol".to_string(),
+        },
+        name => name.to_string(),
+    };
+
+    Ok(format!(
+        "{}+{:#x}: {} against <{}>",
+        sec_name,
+        offset,
+        mips::reloc_name(reloc),
+        sym_name
+    ))
+}
+
+fn relocate(buf: &mut [u8], relocations: &[(usize, u32)]) -> Result<()> {
     let mut iter = relocations.iter().copied().peekable();
 
     while let Some(reloc) = iter.next() {
@@ -267,7 +546,7 @@ fn relocate(buf: &mut Vec<u8>, relocations: &[(usize, u32)]) -> Result<()> {
     Ok(())
 }
 
-fn apply_relocation(buf: &mut Vec<u8>, (loc, val): (usize, u32), next: Option<u32>) -> Result<()> {
+fn apply_relocation(buf: &mut [u8], (loc, val): (usize, u32), next: Option<u32>) -> Result<()> {
     let ptr = buf
         .get_mut(loc..loc + 4)
         .ok_or_else(|| anyhow!("{}-{} was outside of buffer", loc, loc + 4))?;
@@ -282,15 +561,14 @@ fn apply_relocation(buf: &mut Vec<u8>, (loc, val): (usize, u32), next: Option<u3
     Ok(())
 }
 
-fn compress_data(original: &[u8], settings: Option<&VpkSettings>) -> Result<Vec<u8>> {
-    let method = settings
-        .and_then(|s| s.method)
-        .map(|m| match m {
-            0 => VpkMethod::OneSample,
-            1 => VpkMethod::TwoSample,
-            _ => panic!("Unknown method {}", m),
-        })
-        .unwrap_or(VpkMethod::OneSample);
+/// Compress `original` with vpk0, using the method and trees in `settings`.
+/// The `excess` bytes in `settings` are not added. Fails on a method other than 0 or 1.
+pub fn compress_data(original: &[u8], settings: Option<&VpkSettings>) -> Result<Vec<u8>> {
+    let method = match settings.and_then(|s| s.method) {
+        None | Some(0) => VpkMethod::OneSample,
+        Some(1) => VpkMethod::TwoSample,
+        Some(m) => bail!("unknown vpk0 method {}; expected 0 or 1", m),
+    };
 
     Encoder::for_bytes(original)
         .method(method)
@@ -317,7 +595,7 @@ fn add_externs(v: &mut Vec<u8>, externs: &[u16]) {
 fn align_buffer(v: &mut Vec<u8>) {
     const ALIGNMENT: usize = 4;
 
-    while v.len() % ALIGNMENT != 0 {
+    while !v.len().is_multiple_of(ALIGNMENT) {
         v.push(0);
     }
 }
@@ -362,7 +640,7 @@ fn terminate_table(table: &mut Vec<u8>, end: usize) -> Result<()> {
 
 /// Reduce a u32 (like an N64 o32 pointer) to a 16bit word offset
 fn shorten(x: u32) -> Result<u16> {
-    if x % 4 != 0 {
+    if !x.is_multiple_of(4) {
         Err(anyhow!("{} was not in word (four byte) alignment ", x))
     } else {
         u16::try_from(x / 4).with_context(|| format!("{} / 4 = {} is too large for u16", x, x / 4))
@@ -1,8 +1,18 @@
-use anyhow::{anyhow, Result};
-use std::{ffi::OsStr, path::PathBuf};
+use anyhow::{anyhow, bail, Context, Result};
+use halld::{Diagnostics, Linker, LinkerConfig, ObjectSettings};
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-mod cache;
-mod link;
+mod archive;
+mod cache_cmd;
+mod inject;
+mod unlink;
+mod verify;
 
 const DESC: &str = "A linker for HAL's filesystem in SSB64";
 
@@ -14,6 +24,10 @@ fn print_usage() {
 
         Usage:
             {bin} [options] [-L dir]... <script> [-o output.o]
+            {bin} unlink [unlink-options] <linked> -o <dir>
+            {bin} inject [inject-options] [-L dir]... <script> --rom <rom> --at <offset>
+                         --table-at <offset>
+            {bin} cache (stats | prune --max-size <size> | gc --script <script>) -k <dir>
             {bin} -h | --help
             {bin} -V | --version
         
@@ -27,9 +41,65 @@ fn print_usage() {
                                    settings.output field in <script>
             -c --header            Path to output a C header file with file id defines
             -d --dependency-file   Path to output a Makefile dep (.d) file
+            -m --map               Path to output a link map; written as JSON if
+                                   the path ends with .json
+            --diagnostics          Path to output every duplicate symbol, undefined
+                                   symbol, and unresolved import as JSON
             -k --cache             Path to a cache directory for compressed data
+            --remote-cache         URL of an HTTP server to share the cache through
+            --verify               Path to a reference linked object or raw file table
+                                   and data to compare the link against
+            --format               Output format: elf (default), bin, asm, or c
+            --data-output          Path to output the file data separately; the file
+                                   table is written to the output. For asm, this is
+                                   the .incbin file, which defaults to the output
+                                   with a .bin extension
+
+        Unlink:
+            Split a linked object or raw file table back into files and a <script>
+            <linked>    path to a linked object, or a raw binary (like a ROM)
+
+            -o --output            Directory for the unlinked files and script.json
+            -s --script            Path to output the JSON linker script instead
+            -t --table-at          Offset of the file table in a raw binary
+            -f --files-at          Offset of the file data in a raw binary; defaults to
+                                   the end of the file table
+            -n --count             Number of files in the table; defaults to reading
+                                   until the terminating table entry
+            -k --cache             Path to a cache directory to store the original
+                                   compressed data for byte-matching relinks
+            --table-section        Name of the file table section in a linked object
+            --files-section        Name of the file data section in a linked object
+
+        Inject:
+            Link <script> and write the file table and data into a .z64 ROM, growing
+            the ROM if needed
+
+            --rom                  Path to the ROM to inject into
+            --at                   Offset in the ROM for the file data
+            --table-at             Offset in the ROM for the file table
+            -o --output            Path to output the ROM; defaults to overwriting --rom
+            -L --search-dir        Directories to search for files named in <script>
+            -k --cache             Path to a cache directory for compressed data
+            --remote-cache         URL of an HTTP server to share the cache through
+            --limit                Refuse to write the table or data past this offset
+            --crc                  Update the header checksums for a ROM that boots
+                                   with this CIC, like 6102
+        
+        Cache:
+            Maintain a cache directory of compressed data
+
+            stats                  Print the number of entries, their size, and the
+                                   hit rate of the last link that used the cache
+            prune                  Delete the least recently used entries until the
+                                   cache fits in --max-size (like 512M or 2G)
+            gc                     Delete every entry that --script doesn't use
+            -k --cache             Path to an existing cache directory
+            -L --search-dir        Directories to search for files named in --script
         
         Flags:
+            -r --relocatable       Relocate the file table offsets and symbols against
+                                   .files, so the final link can place it anywhere
             -h --help              Print this help message
             -V --version           Print version information
 
@@ -56,12 +126,81 @@ struct RunOpt {
     output: Option<PathBuf>,
     header: Option<PathBuf>,
     cache: Option<PathBuf>,
+    remote_cache: Option<String>,
     mdep: Option<PathBuf>,
+    map: Option<PathBuf>,
+    diagnostics: Option<PathBuf>,
+    verify: Option<PathBuf>,
+    relocatable: bool,
+    format: OutputFormat,
+    data_output: Option<PathBuf>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum OutputFormat {
+    Elf,
+    Bin,
+    Asm,
+    C,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "elf" => Ok(Self::Elf),
+            "bin" => Ok(Self::Bin),
+            "asm" => Ok(Self::Asm),
+            "c" => Ok(Self::C),
+            _ => Err(anyhow!(
+                "unknown output format \"{}\"; expected elf, bin, asm, or c",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct UnlinkOpt {
+    input: PathBuf,
+    output: PathBuf,
+    script: Option<PathBuf>,
+    table_at: Option<usize>,
+    files_at: Option<usize>,
+    count: Option<usize>,
+    cache: Option<PathBuf>,
+    /// names of the sections in a linked object
+    sections: ObjectSettings,
+}
+
+#[derive(Debug)]
+struct InjectOpt {
+    config: PathBuf,
+    search: Option<Vec<PathBuf>>,
+    cache: Option<PathBuf>,
+    remote_cache: Option<String>,
+    rom: PathBuf,
+    output: Option<PathBuf>,
+    files_at: usize,
+    table_at: usize,
+    limit: Option<usize>,
+    cic: Option<u32>,
+}
+
+#[derive(Debug)]
+struct CacheOpt {
+    cache: PathBuf,
+    search: Option<Vec<PathBuf>>,
+    cmd: cache_cmd::CacheCmd,
 }
 
 #[derive(Debug)]
 enum Opt {
     Run(RunOpt),
+    Unlink(UnlinkOpt),
+    Inject(InjectOpt),
+    Cache(CacheOpt),
     Help,
     Version,
 }
@@ -78,23 +217,37 @@ impl Opt {
             return Ok(Self::Version);
         }
 
-        let search = args.values_from_os_str(["-L", "--search-dir"], to_pathbuf)?;
-        let search = if search.is_empty() {
-            None
-        } else {
-            Some(search)
+        // an unknown subcommand is the path to the linker script
+        let config = match args.subcommand()? {
+            Some(cmd) if cmd == "unlink" => return Self::unlink_from_args(args),
+            Some(cmd) if cmd == "inject" => return Self::inject_from_args(args),
+            Some(cmd) if cmd == "cache" => return Self::cache_from_args(args),
+            cmd => cmd.map(PathBuf::from),
         };
+
+        let search = search_dirs_from_args(&mut args)?;
         let output = args.opt_value_from_os_str(["-o", "--output"], to_pathbuf)?;
         let header = args.opt_value_from_os_str(["-c", "--header"], to_pathbuf)?;
         let mdep = args.opt_value_from_os_str(["-d", "--dependency-file"], to_pathbuf)?;
+        let map = args.opt_value_from_os_str(["-m", "--map"], to_pathbuf)?;
+        let diagnostics = args.opt_value_from_os_str("--diagnostics", to_pathbuf)?;
         let cache = args.opt_value_from_os_str(["-k", "--cache"], to_pathbuf)?;
+        let remote_cache = args.opt_value_from_str("--remote-cache")?;
+        let verify = args.opt_value_from_os_str("--verify", to_pathbuf)?;
+        let relocatable = args.contains(["-r", "--relocatable"]);
+        let format = args
+            .opt_value_from_str("--format")?
+            .unwrap_or(OutputFormat::Elf);
+        let data_output = args.opt_value_from_os_str("--data-output", to_pathbuf)?;
 
-        let config = args
-            .finish()
-            .into_iter()
-            .next()
-            .map(PathBuf::from)
+        let mut rest = args.finish().into_iter();
+        let config = config
+            .or_else(|| rest.next().map(PathBuf::from))
             .ok_or_else(|| anyhow!("Path to config JSON file not passed. Use \'-h\' for help"))?;
+        let rest = rest.collect::<Vec<_>>();
+        if !rest.is_empty() {
+            bail!("unexpected arguments: {:?}", rest);
+        }
 
         Ok(Self::Run(RunOpt {
             config,
@@ -102,9 +255,134 @@ impl Opt {
             output,
             header,
             cache,
+            remote_cache,
             mdep,
+            map,
+            diagnostics,
+            verify,
+            relocatable,
+            format,
+            data_output,
         }))
     }
+
+    fn unlink_from_args(mut args: pico_args::Arguments) -> Result<Self> {
+        let output = args
+            .opt_value_from_os_str(["-o", "--output"], to_pathbuf)?
+            .ok_or_else(|| {
+                anyhow!("Output directory for unlink not passed. Use \'-h\' for help")
+            })?;
+        let script = args.opt_value_from_os_str(["-s", "--script"], to_pathbuf)?;
+        let table_at = args.opt_value_from_fn(["-t", "--table-at"], parse_offset)?;
+        let files_at = args.opt_value_from_fn(["-f", "--files-at"], parse_offset)?;
+        let count = args.opt_value_from_fn(["-n", "--count"], parse_offset)?;
+        let cache = args.opt_value_from_os_str(["-k", "--cache"], to_pathbuf)?;
+        let sections = ObjectSettings {
+            table_section: args.opt_value_from_str("--table-section")?,
+            files_section: args.opt_value_from_str("--files-section")?,
+            ..Default::default()
+        };
+
+        let input = args
+            .finish()
+            .into_iter()
+            .next()
+            .map(PathBuf::from)
+            .ok_or_else(|| anyhow!("Path to linked file not passed. Use \'-h\' for help"))?;
+
+        Ok(Self::Unlink(UnlinkOpt {
+            input,
+            output,
+            script,
+            table_at,
+            files_at,
+            count,
+            cache,
+            sections,
+        }))
+    }
+
+    fn inject_from_args(mut args: pico_args::Arguments) -> Result<Self> {
+        let missing = |opt: &str| anyhow!("{} for inject not passed. Use \'-h\' for help", opt);
+
+        let rom = args
+            .opt_value_from_os_str("--rom", to_pathbuf)?
+            .ok_or_else(|| missing("Path to the ROM (--rom)"))?;
+        let files_at = args
+            .opt_value_from_fn("--at", parse_offset)?
+            .ok_or_else(|| missing("Offset of the file data (--at)"))?;
+        let table_at = args
+            .opt_value_from_fn("--table-at", parse_offset)?
+            .ok_or_else(|| missing("Offset of the file table (--table-at)"))?;
+        let output = args.opt_value_from_os_str(["-o", "--output"], to_pathbuf)?;
+        let search = search_dirs_from_args(&mut args)?;
+        let cache = args.opt_value_from_os_str(["-k", "--cache"], to_pathbuf)?;
+        let remote_cache = args.opt_value_from_str("--remote-cache")?;
+        let limit = args.opt_value_from_fn("--limit", parse_offset)?;
+        let cic = args.opt_value_from_fn("--crc", inject::parse_cic)?;
+
+        let config = args
+            .finish()
+            .into_iter()
+            .next()
+            .map(PathBuf::from)
+            .ok_or_else(|| missing("Path to config JSON file"))?;
+
+        Ok(Self::Inject(InjectOpt {
+            config,
+            search,
+            cache,
+            remote_cache,
+            rom,
+            output,
+            files_at,
+            table_at,
+            limit,
+            cic,
+        }))
+    }
+
+    fn cache_from_args(mut args: pico_args::Arguments) -> Result<Self> {
+        let missing = |opt: &str| anyhow!("{} for cache not passed. Use \'-h\' for help", opt);
+
+        let cmd = args
+            .subcommand()?
+            .ok_or_else(|| missing("Command (stats, prune, or gc)"))?;
+        let cache = args
+            .opt_value_from_os_str(["-k", "--cache"], to_pathbuf)?
+            .ok_or_else(|| missing("Path to the cache directory (-k)"))?;
+        let search = search_dirs_from_args(&mut args)?;
+        let cmd = match cmd.as_str() {
+            "stats" => cache_cmd::CacheCmd::Stats,
+            "prune" => cache_cmd::CacheCmd::Prune {
+                max_size: args
+                    .opt_value_from_fn("--max-size", cache_cmd::parse_size)?
+                    .ok_or_else(|| missing("Maximum size (--max-size)"))?,
+            },
+            "gc" => cache_cmd::CacheCmd::Gc {
+                script: args
+                    .opt_value_from_os_str("--script", to_pathbuf)?
+                    .ok_or_else(|| missing("Path to the linker script (--script)"))?,
+            },
+            _ => bail!(
+                "unknown cache command \"{}\"; expected stats, prune, or gc",
+                cmd
+            ),
+        };
+
+        let rest = args.finish();
+        if !rest.is_empty() {
+            bail!("unexpected arguments for cache: {:?}", rest);
+        }
+
+        Ok(Self::Cache(CacheOpt { cache, search, cmd }))
+    }
+}
+
+fn search_dirs_from_args(args: &mut pico_args::Arguments) -> Result<Option<Vec<PathBuf>>> {
+    let search = args.values_from_os_str(["-L", "--search-dir"], to_pathbuf)?;
+
+    Ok((!search.is_empty()).then_some(search))
 }
 
 fn main() -> Result<()> {
@@ -119,10 +397,168 @@ fn main() -> Result<()> {
             print_version();
             Ok(())
         }
-        Opt::Run(opts) => link::run(opts),
+        Opt::Run(opts) => link(opts),
+        Opt::Unlink(opts) => unlink::run(opts),
+        Opt::Inject(opts) => inject::run(opts),
+        Opt::Cache(opts) => cache_cmd::run(opts),
+    }
+}
+
+fn link(opts: RunOpt) -> Result<()> {
+    let RunOpt {
+        config,
+        search,
+        output,
+        header,
+        mdep,
+        map,
+        diagnostics,
+        cache,
+        remote_cache,
+        verify,
+        relocatable,
+        format,
+        data_output,
+    } = opts;
+
+    let mut script = read_config(&config)?;
+
+    let config_output = script.settings.as_mut().and_then(|s| s.output.take());
+    if relocatable {
+        let settings = script.settings.get_or_insert_with(Default::default);
+        settings
+            .object
+            .get_or_insert_with(Default::default)
+            .relocatable = Some(true);
+    }
+    let output = output
+        .or(config_output)
+        .ok_or_else(|| anyhow!("no output location from JSON or from CLI"))?;
+
+    // check the file data location before linking, so nothing is written for a bad combination
+    let data_output = match (format, data_output) {
+        (OutputFormat::Asm, data_path) => {
+            let data_path = data_path.unwrap_or_else(|| output.with_extension("bin"));
+            if data_path == output {
+                bail!(
+                    "the file data would overwrite the output < {} >",
+                    output.display()
+                );
+            }
+            Some(data_path)
+        }
+        (OutputFormat::Bin, data_path) => data_path,
+        (format, Some(_)) => bail!("--data-output can't be used with {:?} output", format),
+        (_, None) => None,
+    };
+
+    let linked = Linker::new(script)
+        .search_dirs(search.unwrap_or_default())
+        .optional_cache(cache)
+        .optional_remote_cache(remote_cache)
+        .link();
+
+    // any other error means the link didn't get far enough to check for diagnostics
+    let found = match &linked {
+        Ok(_) => Some(Diagnostics::default()),
+        Err(e) => e.downcast_ref::<Diagnostics>().cloned(),
+    };
+    match (diagnostics, found) {
+        (Some(p), Some(found)) => {
+            let wtr = BufWriter::new(File::create(p).context("creating diagnostics file")?);
+            serde_json::to_writer_pretty(wtr, &found).context("writing diagnostics")?;
+        }
+        // don't leave an empty array from an earlier link that succeeded
+        (Some(p), None) => match fs::remove_file(&p) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                eprintln!("warning: couldn't remove < {} >: {}", p.display(), e)
+            }
+            _ => (),
+        },
+        (None, _) => (),
+    }
+    let linked = linked?;
+
+    let mismatched = verify
+        .map(|reference| {
+            let sections = &linked.object;
+            verify::verify(
+                &linked.table,
+                &linked.data,
+                &linked.inputs,
+                &reference,
+                sections,
+            )
+        })
+        .transpose()
+        .context("verifying link against reference")?;
+
+    if let Some(file) = header {
+        let mut wtr = BufWriter::new(File::create(file).context("creating c header file")?);
+        linked
+            .write_c_header(&mut wtr, &output)
+            .context("writing defines to c header")?;
+    }
+    if let Some(p) = mdep {
+        let mut wtr = BufWriter::new(File::create(p).context("creating make dependencies file")?);
+        linked
+            .write_make_dep(&mut wtr, &output, &config)
+            .context("writing dependencies to makefile")?;
+    }
+
+    if let Some(p) = map {
+        let mut wtr = BufWriter::new(File::create(&p).context("creating link map file")?);
+        if p.extension().is_some_and(|ext| ext == "json") {
+            linked.write_map_json(wtr)?;
+        } else {
+            linked.write_map(&mut wtr).context("writing link map")?;
+        }
+    }
+
+    let mut wtr = BufWriter::new(File::create(&output).context("making output file")?);
+    match (format, data_output) {
+        (OutputFormat::Elf, _) => linked.write_object(&mut wtr)?,
+        (OutputFormat::Bin, None) => linked.write_bin(&mut wtr)?,
+        (OutputFormat::Bin, Some(data_path)) => {
+            wtr.write_all(&linked.table)?;
+            fs::write(&data_path, &linked.data)
+                .with_context(|| format!("writing file data to < {} >", data_path.display()))?;
+        }
+        (OutputFormat::Asm, data_path) => {
+            let data_path = data_path.expect("asm output always has a data path");
+            linked.write_asm(&mut wtr, &data_path)?;
+            fs::write(&data_path, &linked.data)
+                .with_context(|| format!("writing file data to < {} >", data_path.display()))?;
+        }
+        (OutputFormat::C, _) => linked.write_c(&mut wtr)?,
+    }
+    wtr.flush().context("writing output file")?;
+
+    match mismatched {
+        Some(n) if n > 0 => Err(anyhow!("{} files did not match the reference", n)),
+        _ => Ok(()),
     }
 }
 
+fn read_config(config: &Path) -> Result<LinkerConfig> {
+    let rdr = BufReader::new(
+        File::open(config)
+            .with_context(|| format!("couldn't open config script at <{}>", config.display()))?,
+    );
+
+    serde_json::from_reader(rdr).context("parsing config JSON")
+}
+
 fn to_pathbuf(s: &OsStr) -> Result<PathBuf> {
     Ok(PathBuf::from(s))
 }
+
+/// Parse a decimal or `0x` prefixed hexadecimal number
+fn parse_offset(s: &str) -> Result<usize> {
+    let n = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+
+    n.with_context(|| format!("parsing < {} > as a number", s))
+}
@@ -0,0 +1,215 @@
+use crate::archive::{self, Archive, ArchivedFile};
+use halld::{DataCache, FileRef, FileType, InputFile, LinkerConfig, LinkerSettings, VpkSettings};
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Cursor},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use vpk0::Decoder;
+
+pub(crate) fn run(opts: crate::UnlinkOpt) -> Result<()> {
+    let crate::UnlinkOpt {
+        input,
+        output,
+        script,
+        table_at,
+        files_at,
+        count,
+        cache,
+        sections,
+    } = opts;
+
+    let bytes = fs::read(&input).with_context(|| format!("reading < {} >", input.display()))?;
+    let archive = if let Some(table_at) = table_at {
+        Archive::from_raw(&bytes, table_at, files_at, count)
+    } else if bytes.starts_with(b"\x7fELF") {
+        Archive::from_object(&bytes, &sections)
+    } else {
+        Archive::from_raw(&bytes, 0, files_at, count)
+    }
+    .with_context(|| format!("reading file table from < {} >", input.display()))?;
+
+    fs::create_dir_all(&output)
+        .with_context(|| format!("creating output directory < {} >", output.display()))?;
+    let vpk_cache = cache
+        .clone()
+        .map(DataCache::new)
+        .transpose()
+        .context("creating vpk compression cache")?;
+
+    let mut linker_script = Vec::with_capacity(archive.len());
+    let mut expected_offset = 0;
+    for id in 0..archive.len() {
+        let file = archive.file(id)?;
+        if file.entry.offset != expected_offset {
+            eprintln!(
+                "warning: file {} starts at {:#x} instead of {:#x}; relinking will not match",
+                id, file.entry.offset, expected_offset
+            );
+        }
+        expected_offset =
+            file.entry.offset + file.payload.len() as u32 + file.externs.len() as u32 * 2;
+
+        // the linker fills unused ids with empty entries
+        if file.entry.size == 0 && file.payload.is_empty() && file.externs.is_empty() {
+            continue;
+        }
+
+        let name = PathBuf::from(format!("{:04}.bin", id));
+        let (mut entry, data) = unlink_file(&file, &name, vpk_cache.as_ref())
+            .with_context(|| format!("unlinking file {}", id))?;
+        entry.id = Some(id as u16);
+        fs::write(output.join(&name), data)
+            .with_context(|| format!("writing file {} to < {} >", id, name.display()))?;
+
+        linker_script.push(entry);
+    }
+
+    let config = LinkerConfig {
+        settings: Some(LinkerSettings {
+            search_dirs: Some(vec![output.clone()]),
+            output: None,
+            cache,
+            remote_cache: None,
+            // relink into the same sections
+            object: (sections.table_section.is_some() || sections.files_section.is_some())
+                .then_some(sections),
+        }),
+        script: linker_script,
+    };
+
+    let script = script.unwrap_or_else(|| output.join("script.json"));
+    let wtr = BufWriter::new(File::create(&script).context("creating linker script")?);
+    serde_json::to_writer_pretty(wtr, &config).context("writing linker script JSON")?;
+
+    Ok(())
+}
+
+/// Split out the raw data for `file`, and recover the `InputFile` settings needed to relink it
+fn unlink_file(
+    file: &ArchivedFile,
+    name: &Path,
+    cache: Option<&DataCache>,
+) -> Result<(InputFile, Vec<u8>)> {
+    let ArchivedFile {
+        entry,
+        payload,
+        externs,
+    } = file;
+
+    let (data, comp_settings) = if entry.compressed {
+        let (data, settings) = decompress(payload, name, cache)?;
+        (data, Some(settings))
+    } else {
+        (payload.to_vec(), None)
+    };
+
+    let aligned_size = (data.len() as u32 + 3) & !3;
+    if aligned_size != entry.size {
+        bail!(
+            "file data is {} bytes, but the table entry has a size of {} bytes",
+            data.len(),
+            entry.size
+        );
+    }
+
+    // validate the relocation chains
+    archive::decode_reloc_chain(&data, entry.inreloc).context("decoding internal relocations")?;
+    let exrelocs = archive::decode_reloc_chain(&data, entry.exreloc)
+        .context("decoding external relocations")?;
+    if exrelocs.len() != externs.len() {
+        eprintln!(
+            "warning: < {} > has {} external relocations but {} extern file ids",
+            name.display(),
+            exrelocs.len(),
+            externs.len()
+        );
+    }
+
+    let input = InputFile {
+        file: name.to_path_buf(),
+        id: None,
+        compressed: entry.compressed,
+        comp_settings,
+        inreloc: entry.inreloc,
+        exreloc: entry.exreloc,
+        exports: None,
+        imports: (!externs.is_empty()).then(|| externs.iter().copied().map(FileRef::Id).collect()),
+        relocs: None,
+        // the data could start with an ELF header by chance
+        file_type: Some(FileType::Raw),
+    };
+
+    Ok((input, data))
+}
+
+/// Decompress a vpk0 `payload` and recover the settings needed to recompress it.
+/// If `cache` is passed, the original vpk0 stream is stored there so that a relink
+/// reproduces it even if the encoder does not match the original compression.
+fn decompress(
+    payload: &[u8],
+    name: &Path,
+    cache: Option<&DataCache>,
+) -> Result<(Vec<u8>, VpkSettings)> {
+    let mut rdr = Cursor::new(payload);
+    let mut decoder = Decoder::for_reader(&mut rdr);
+    let header = decoder.header().context("reading vpk0 header")?;
+    let trees = decoder.trees().context("reading vpk0 trees")?;
+    let data = decoder.decode().context("decoding vpk0 data")?;
+    drop(decoder);
+
+    let decoded_len = rdr.position() as usize;
+    let method = Some(header.method as u8);
+
+    let mut aligned = data.clone();
+    aligned.resize((data.len() + 3) & !3, 0);
+
+    // prefer the original trees, but the encoder may pick the same trees by itself.
+    // if neither reproduces the original data, the stream ends where the decoder stopped
+    let candidates = [(Some(trees.offsets), Some(trees.lengths)), (None, None)];
+    let matched = candidates.into_iter().find_map(|(offsets, lengths)| {
+        let settings = VpkSettings {
+            method,
+            offsets,
+            lengths,
+            excess: None,
+        };
+        let recompressed = halld::compress_data(&aligned, Some(&settings)).ok()?;
+        payload
+            .starts_with(&recompressed)
+            .then_some((settings, recompressed.len()))
+    });
+
+    if matched.is_none() && cache.is_none() {
+        eprintln!(
+            "warning: recompressing < {} > does not match its original vpk0 data; \
+            pass a cache directory with -k to keep the original data",
+            name.display()
+        );
+    }
+
+    let (mut settings, stream_len) = matched.unwrap_or_else(|| {
+        let settings = VpkSettings {
+            method,
+            offsets: None,
+            lengths: None,
+            excess: None,
+        };
+        (settings, decoded_len)
+    });
+
+    // anything after the vpk0 stream is either padding or extra data
+    let (stream, rest) = payload.split_at(stream_len);
+    let only_padding = rest.iter().all(|&b| b == 0) && (stream_len + 3) & !3 == payload.len();
+    settings.excess = (!only_padding).then(|| rest.to_vec());
+
+    if let Some(c) = cache {
+        c.write(&aligned, Some(&settings), name, stream)
+            .context("caching original compressed data")?;
+    }
+
+    Ok((data, settings))
+}
@@ -0,0 +1,132 @@
+use crate::archive::{Archive, ArchivedFile, TableEntry};
+use halld::ObjectSettings;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+/// Compare a linked table and data against the reference archive at `reference`,
+/// and print every difference per file. A reference object has its sections named
+/// like `sections`. Returns the number of files that differ.
+pub(crate) fn verify(
+    table: &[u8],
+    data: &[u8],
+    inputs: &[Option<PathBuf>],
+    reference: &Path,
+    sections: &ObjectSettings,
+) -> Result<usize> {
+    let bytes = fs::read(reference)
+        .with_context(|| format!("reading reference < {} >", reference.display()))?;
+    let expected = if bytes.starts_with(b"\x7fELF") {
+        Archive::from_object(&bytes, sections)
+    } else {
+        Archive::from_raw(&bytes, 0, None, None)
+    }
+    .with_context(|| format!("reading file table from < {} >", reference.display()))?;
+    let linked = Archive::from_parts(table, data).context("reading linked file table")?;
+
+    if linked.len() != expected.len() {
+        println!(
+            "linked {} files, but the reference has {} files",
+            linked.len(),
+            expected.len()
+        );
+    }
+
+    let mut mismatched = 0;
+    for (id, input) in inputs.iter().enumerate().take(expected.len()) {
+        let diffs = compare_file(&linked.file(id)?, &expected.file(id)?);
+        if !diffs.is_empty() {
+            mismatched += 1;
+            match input {
+                Some(input) => println!("file {} < {} > does not match:", id, input.display()),
+                None => println!("empty file {} does not match:", id),
+            }
+            for diff in diffs {
+                println!("    {}", diff);
+            }
+        }
+    }
+
+    Ok(mismatched + linked.len().abs_diff(expected.len()))
+}
+
+fn compare_file(linked: &ArchivedFile, expected: &ArchivedFile) -> Vec<String> {
+    let mut diffs = compare_entries(&linked.entry, &expected.entry);
+
+    if linked.entry.compressed != expected.entry.compressed {
+        diffs.push(format!(
+            "compressed {} vs reference {}",
+            linked.entry.compressed, expected.entry.compressed
+        ));
+    }
+
+    if let Some(at) = first_difference(linked.payload, expected.payload) {
+        let both_compressed = linked.entry.compressed && expected.entry.compressed;
+        let raw = both_compressed
+            .then(|| vpk0::decode_bytes(linked.payload).ok())
+            .flatten()
+            .zip(vpk0::decode_bytes(expected.payload).ok());
+
+        match raw {
+            Some((l, e)) => match first_difference(&l, &e) {
+                Some(raw_at) => diffs.push(format!(
+                    "uncompressed data differs at {:#x} (compressed data at {:#x})",
+                    raw_at, at
+                )),
+                None => diffs.push(format!(
+                    "uncompressed data matches, but compressed data differs at {:#x}",
+                    at
+                )),
+            },
+            None => diffs.push(format!("data differs at {:#x}", at)),
+        }
+    }
+
+    if linked.externs != expected.externs {
+        diffs.push(format!(
+            "extern file ids {:?} vs reference {:?}",
+            linked.externs, expected.externs
+        ));
+    }
+
+    diffs
+}
+
+fn compare_entries(linked: &TableEntry, expected: &TableEntry) -> Vec<String> {
+    let fields = [
+        ("offset", Some(linked.offset), Some(expected.offset)),
+        ("inreloc", linked.inreloc, expected.inreloc),
+        ("rom size", Some(linked.rom_size), Some(expected.rom_size)),
+        ("exreloc", linked.exreloc, expected.exreloc),
+        ("size", Some(linked.size), Some(expected.size)),
+    ];
+
+    fields
+        .into_iter()
+        .filter(|(_, l, e)| l != e)
+        .map(|(name, l, e)| {
+            format!(
+                "table {}: {} vs reference {}",
+                name,
+                fmt_field(l),
+                fmt_field(e)
+            )
+        })
+        .collect()
+}
+
+fn fmt_field(f: Option<u32>) -> String {
+    f.map_or_else(|| "none".to_string(), |v| format!("{:#x}", v))
+}
+
+/// Find the first offset where `a` and `b` differ, including a difference in length
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    a.iter()
+        .zip(b)
+        .position(|(x, y)| x != y)
+        .or_else(|| (a.len() != b.len()).then(|| a.len().min(b.len())))
+}
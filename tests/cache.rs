@@ -0,0 +1,434 @@
+use halld::{CacheManifest, DataCache, HttpBackend, Linker, LinkerConfig, RunStats, VpkSettings};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// A fresh directory for one test, removed when the test ends
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(test: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("halld-cache-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Self(dir)
+    }
+
+    fn cache(&self) -> DataCache {
+        DataCache::new(self.0.clone()).unwrap()
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn cache_keys_on_compression_settings() {
+    let dir = TempDir::new("keys");
+    let cache = dir.cache();
+    let settings = |method, offsets: Option<&str>| VpkSettings {
+        method,
+        offsets: offsets.map(Into::into),
+        lengths: None,
+        excess: None,
+    };
+
+    let data = [1, 2, 3, 4];
+    let two_sample = settings(Some(1), None);
+    cache
+        .write(&data, Some(&two_sample), "data.bin".as_ref(), b"two")
+        .unwrap();
+
+    assert_eq!(cache.read(&data, Some(&two_sample)).unwrap(), b"two");
+    assert!(cache.read(&data, None).is_none());
+    assert!(cache
+        .read(&data, Some(&settings(Some(1), Some("(4, 8)"))))
+        .is_none());
+    assert!(cache.read(&[1, 2, 3, 5], Some(&two_sample)).is_none());
+
+    // the default method is the same as one sample
+    cache
+        .write(&data, None, "data.bin".as_ref(), b"one")
+        .unwrap();
+    assert_eq!(
+        cache.read(&data, Some(&settings(Some(0), None))).unwrap(),
+        b"one"
+    );
+
+    let manifests = fs::read_dir(dir.join("v2"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+        .map(|p| serde_json::from_slice::<CacheManifest>(&fs::read(p).unwrap()).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(manifests.len(), 2);
+    assert!(manifests
+        .iter()
+        .all(|m| m.input == Path::new("data.bin") && m.size == 4));
+}
+
+#[test]
+fn encoder_matches_cargo_lock() {
+    let lock = fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.lock"))
+        .expect("Cargo.lock is written by cargo test");
+    let version = lock
+        .split("[[package]]")
+        .find(|pkg| pkg.contains("name = \"vpk0\""))
+        .and_then(|pkg| pkg.lines().find_map(|l| l.strip_prefix("version = ")))
+        .expect("vpk0 in Cargo.lock")
+        .trim_matches('"');
+
+    let dir = TempDir::new("encoder");
+    dir.cache()
+        .write(&[1; 16], None, "data.bin".as_ref(), b"data")
+        .unwrap();
+    let key = DataCache::key(&[1; 16], None);
+    let manifest = fs::read(dir.join("v2").join(format!("{}.json", key))).unwrap();
+    let manifest = serde_json::from_slice::<CacheManifest>(&manifest).unwrap();
+    assert_eq!(
+        manifest.encoder,
+        format!("vpk0 {}", version),
+        "update ENCODER in src/cache.rs for the new vpk0"
+    );
+}
+
+#[test]
+fn cache_stats_prune_and_retain() {
+    use std::collections::HashSet;
+
+    let dir = TempDir::new("prune");
+    let cache = dir.cache();
+
+    let inputs = [[1u8; 16], [2; 16], [3; 16]];
+    for (i, data) in inputs.iter().enumerate() {
+        cache
+            .write(data, None, "data.bin".as_ref(), &[0; 64])
+            .unwrap();
+        // the least recently used entry is found by its modified time
+        let path = dir
+            .join("v2")
+            .join(format!("{}.bin", DataCache::key(data, None)));
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000 + i as u64);
+        File::options()
+            .write(true)
+            .open(path)
+            .and_then(|f| f.set_modified(modified))
+            .unwrap();
+    }
+    let stats = cache.stats().unwrap();
+    assert_eq!(stats.entries, 3);
+    let entry_size = stats.bytes / 3;
+
+    // reading the first entry makes the second the least recently used
+    assert!(cache.read(&inputs[0], None).is_some());
+    assert!(cache.read(&[4; 16], None).is_none());
+    cache.save_run_stats().unwrap();
+
+    let stats = cache.stats().unwrap();
+    assert_eq!(stats.last_run, Some(RunStats { hits: 1, misses: 1 }));
+
+    let removed = cache.prune(entry_size * 2).unwrap();
+    assert_eq!(removed.entries, 1);
+    assert!(cache.read(&inputs[1], None).is_none());
+    assert!(cache.read(&inputs[0], None).is_some());
+    assert!(cache.read(&inputs[2], None).is_some());
+
+    // only keep the entries that this cache used
+    let reopened = dir.cache();
+    assert!(reopened.read(&inputs[2], None).is_some());
+    let keep = reopened.used().into_iter().collect::<HashSet<_>>();
+    let removed = reopened.retain(&keep).unwrap();
+    assert_eq!(removed.entries, 1);
+    assert_eq!(reopened.stats().unwrap().entries, 1);
+    assert!(reopened.read(&inputs[2], None).is_some());
+}
+
+#[test]
+fn corrupt_cache_entries_are_discarded() {
+    let dir = TempDir::new("corrupt");
+    let cache = dir.cache();
+    let entries = || {
+        fs::read_dir(dir.join("v2"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>()
+    };
+    let bin = |entries: &[PathBuf]| {
+        entries
+            .iter()
+            .find(|p| p.extension().is_some_and(|ext| ext == "bin"))
+            .cloned()
+            .unwrap()
+    };
+
+    // a truncated write
+    cache
+        .write(&[1; 16], None, "data.bin".as_ref(), &[7; 64])
+        .unwrap();
+    let path = bin(&entries());
+    let full = fs::read(&path).unwrap();
+    fs::write(&path, &full[..full.len() - 1]).unwrap();
+    assert!(cache.read(&[1; 16], None).is_none());
+    assert!(entries().is_empty());
+
+    // a flipped bit
+    cache
+        .write(&[1; 16], None, "data.bin".as_ref(), &[7; 64])
+        .unwrap();
+    let mut flipped = full.clone();
+    *flipped.last_mut().unwrap() ^= 1;
+    fs::write(bin(&entries()), flipped).unwrap();
+    assert!(cache.read(&[1; 16], None).is_none());
+    assert!(entries().is_empty());
+
+    // rewriting a discarded entry works, and leaves no temporary files
+    cache
+        .write(&[1; 16], None, "data.bin".as_ref(), &[7; 64])
+        .unwrap();
+    assert_eq!(cache.read(&[1; 16], None).unwrap(), [7; 64]);
+    assert_eq!(entries().len(), 2);
+}
+
+#[test]
+fn shared_cache_never_reads_partial_entries() {
+    let dir = TempDir::new("shared");
+    // two caches in the same directory, like two halld processes
+    let caches = [dir.cache(), dir.cache()];
+    let compressed = (0..=255).cycle().take(64 * 1024).collect::<Vec<u8>>();
+
+    std::thread::scope(|s| {
+        for i in 0..8 {
+            let cache = &caches[i % 2];
+            let compressed = &compressed;
+            s.spawn(move || {
+                for _ in 0..20 {
+                    cache
+                        .write(&[1; 16], None, "data.bin".as_ref(), compressed)
+                        .unwrap();
+                    if let Some(read) = cache.read(&[1; 16], None) {
+                        assert_eq!(read, *compressed);
+                    }
+                }
+            });
+        }
+    });
+
+    assert_eq!(caches[0].read(&[1; 16], None).unwrap(), compressed);
+    assert_eq!(fs::read_dir(dir.join("v2")).unwrap().count(), 2);
+}
+
+/// An HTTP server that stores whatever is `PUT` to it, like a shared cache server
+struct StandInServer {
+    url: String,
+    files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    server: Arc<tiny_http::Server>,
+}
+
+impl StandInServer {
+    fn start() -> Self {
+        let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+        let url = format!("http://{}/cache", server.server_addr().to_ip().unwrap());
+        let files = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+
+        let (requests, stored) = (Arc::clone(&server), Arc::clone(&files));
+        std::thread::spawn(move || {
+            for mut req in requests.incoming_requests() {
+                let path = req.url().to_string();
+                let resp = match req.method() {
+                    tiny_http::Method::Get => match stored.lock().unwrap().get(&path) {
+                        Some(body) => tiny_http::Response::from_data(body.clone()),
+                        None => tiny_http::Response::from_data(Vec::new()).with_status_code(404),
+                    },
+                    tiny_http::Method::Put => {
+                        let mut body = Vec::new();
+                        std::io::Read::read_to_end(req.as_reader(), &mut body).unwrap();
+                        stored.lock().unwrap().insert(path, body);
+                        tiny_http::Response::from_data(Vec::new())
+                    }
+                    _ => tiny_http::Response::from_data(Vec::new()).with_status_code(405),
+                };
+                let _ = req.respond(resp);
+            }
+        });
+
+        Self { url, files, server }
+    }
+}
+
+impl Drop for StandInServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+    }
+}
+
+#[test]
+fn remote_cache_shares_entries() {
+    let server = StandInServer::start();
+    let dirs = ["a", "b", "c"].map(|name| TempDir::new(&format!("remote-{}", name)));
+    let machine = |i: usize| dirs[i].cache().with_remote(HttpBackend::new(&server.url));
+
+    machine(0)
+        .write(&[1; 16], None, "data.bin".as_ref(), &[7; 64])
+        .unwrap();
+    assert_eq!(server.files.lock().unwrap().len(), 2);
+
+    // another machine finds the entry on the server, and keeps a copy
+    let b = machine(1);
+    assert_eq!(b.read(&[1; 16], None).unwrap(), [7; 64]);
+    assert_eq!(b.stats().unwrap().entries, 1);
+    assert!(b.read(&[2; 16], None).is_none());
+
+    // a corrupt entry on the server is a miss
+    for (path, body) in server.files.lock().unwrap().iter_mut() {
+        if path.ends_with(".bin") {
+            *body.last_mut().unwrap() ^= 1;
+        }
+    }
+    let c = machine(2);
+    assert!(c.read(&[1; 16], None).is_none());
+    assert_eq!(c.stats().unwrap().entries, 0);
+}
+
+#[test]
+fn unreachable_remote_cache_compresses_locally() {
+    // nothing is listening on a port that was just closed
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/cache", closed.local_addr().unwrap());
+    drop(closed);
+
+    let dir = TempDir::new("unreachable");
+    fs::create_dir_all(&*dir).unwrap();
+    fs::write(dir.join("data.bin"), [0x12; 256]).unwrap();
+    let config = || {
+        serde_json::from_str::<LinkerConfig>(
+            r#"{ "script": [{ "file": "data.bin", "compressed": true }] }"#,
+        )
+        .unwrap()
+    };
+
+    let out = Linker::new(config())
+        .search_dir(&*dir)
+        .cache(dir.join("cache"))
+        .remote_cache(url.as_str())
+        .link()
+        .unwrap();
+    assert_eq!(out.cache_keys.len(), 1);
+    let cache = DataCache::new(dir.join("cache")).unwrap();
+    assert_eq!(cache.stats().unwrap().entries, 1);
+
+    // the remote cache is only used with a local one
+    let err = Linker::new(config())
+        .search_dir(&*dir)
+        .remote_cache(url.as_str())
+        .link()
+        .unwrap_err();
+    assert!(err.to_string().contains("needs a cache directory"));
+}
+
+#[test]
+fn legacy_cache_entries_are_ignored_then_pruned() {
+    let dir = TempDir::new("legacy");
+    fs::create_dir_all(&*dir).unwrap();
+    // an entry named by MD5, before the layout was versioned
+    let legacy = "0123456789abcdef0123456789abcdef";
+    fs::write(dir.join(format!("{}.bin", legacy)), [7; 64]).unwrap();
+    fs::write(dir.join(format!("{}.json", legacy)), "{}").unwrap();
+
+    let cache = dir.cache();
+    let key = DataCache::key(&[1; 16], None);
+    assert_eq!(key.len(), 64);
+    assert!(cache.read(&[1; 16], None).is_none());
+    cache
+        .write(&[1; 16], None, "data.bin".as_ref(), &[7; 64])
+        .unwrap();
+    assert!(dir.join("v2").join(format!("{}.bin", key)).exists());
+
+    let stats = cache.stats().unwrap();
+    assert_eq!((stats.entries, stats.legacy_entries), (1, 1));
+    assert_eq!(stats.legacy_bytes, 66);
+
+    // the legacy entry is pruned first, even though it is smaller
+    let removed = cache.prune(stats.bytes).unwrap();
+    assert_eq!(removed.entries, 1);
+    assert!(!dir.join(format!("{}.bin", legacy)).exists());
+    assert!(!dir.join(format!("{}.json", legacy)).exists());
+    assert_eq!(cache.read(&[1; 16], None).unwrap(), [7; 64]);
+}
+
+#[test]
+fn abandoned_writes_are_swept() {
+    let dir = TempDir::new("sweep");
+    let cache = dir.cache();
+    cache
+        .write(&[1; 16], None, "data.bin".as_ref(), &[0; 64])
+        .unwrap();
+
+    let v2 = dir.join("v2");
+    let orphan = format!("{}.json", DataCache::key(&[2; 16], None));
+    let files = [(".a.bin.1-0.tmp", 10), (orphan.as_str(), 20)];
+    for (name, len) in files {
+        fs::write(v2.join(name), vec![0; len]).unwrap();
+        File::options()
+            .write(true)
+            .open(v2.join(name))
+            .and_then(|f| f.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000)))
+            .unwrap();
+    }
+    // a writer may still be working on these
+    fs::write(v2.join(".b.bin.1-1.tmp"), [0; 4]).unwrap();
+    fs::write(
+        v2.join(format!("{}.json", DataCache::key(&[3; 16], None))),
+        [0; 4],
+    )
+    .unwrap();
+
+    let removed = cache.prune(u64::MAX).unwrap();
+    assert_eq!((removed.entries, removed.bytes), (0, 30));
+    assert_eq!(fs::read_dir(&v2).unwrap().count(), 4);
+    assert!(files.iter().all(|(name, _)| !v2.join(name).exists()));
+    assert!(cache.read(&[1; 16], None).is_some());
+}
+
+#[test]
+fn legacy_entries_are_only_md5_names() {
+    use std::collections::HashSet;
+
+    let dir = TempDir::new("legacy-md5");
+    let cache = dir.cache();
+    let md5 = "0123456789abcdef0123456789abcdef";
+    fs::write(dir.join(format!("{}.bin", md5)), [0; 16]).unwrap();
+    fs::write(dir.join(format!("{}.json", md5)), [0; 8]).unwrap();
+    // files that someone else keeps in the cache directory
+    let others = [
+        "rom.bin".to_string(),
+        "rom.json".to_string(),
+        format!("{}.bin", md5.to_uppercase()),
+        format!("{}.bin", &md5[1..]),
+    ];
+    for name in &others {
+        fs::write(dir.join(name), [0; 4]).unwrap();
+    }
+    fs::create_dir(dir.join(format!("{}.bin", md5.replace('0', "1")))).unwrap();
+
+    let stats = cache.stats().unwrap();
+    assert_eq!((stats.legacy_entries, stats.legacy_bytes), (1, 24));
+    let removed = cache.retain(&HashSet::new()).unwrap();
+    assert_eq!((removed.entries, removed.bytes), (1, 24));
+    assert!(!dir.join(format!("{}.json", md5)).exists());
+    assert!(others.iter().all(|name| dir.join(name).exists()));
+}
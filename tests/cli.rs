@@ -0,0 +1,665 @@
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+/// A fresh directory for one test, removed when the test ends
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new(test: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("halld-cli-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    fn write(&self, name: &str, contents: impl AsRef<[u8]>) {
+        fs::write(self.0.join(name), contents).unwrap();
+    }
+
+    fn read(&self, name: &str) -> Vec<u8> {
+        fs::read(self.0.join(name)).unwrap()
+    }
+
+    /// Run `halld` in this directory
+    fn halld(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_halld"))
+            .args(args)
+            .current_dir(&self.0)
+            .output()
+            .unwrap()
+    }
+
+    /// Run `halld` in this directory, and panic if it fails
+    fn halld_ok(&self, args: &[&str]) -> Output {
+        let out = self.halld(args);
+        assert!(
+            out.status.success(),
+            "halld {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&out.stderr)
+        );
+        out
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn words(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|w| w.to_be_bytes()).collect()
+}
+
+fn stderr(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stderr).into_owned()
+}
+
+/// Files with both relocation chains, an import, a compressed file, and an unused id
+fn write_archive_inputs(dir: &TestDir) {
+    // word 0 -> word 3, then word 1 -> word 2
+    dir.write(
+        "a.bin",
+        words(&[0x0001_0003, 0xFFFF_0002, 0x1111_1111, 0x2222_2222]),
+    );
+    // word 1 -> word 1 of file 0
+    dir.write("b.bin", words(&[0x3333_3333, 0xFFFF_0001]));
+    dir.write("c.bin", (0..=255u8).cycle().take(512).collect::<Vec<_>>());
+    dir.write(
+        "script.json",
+        r#"{ "script": [
+            { "file": "a.bin", "id": 0, "compressed": false, "inreloc": 0 },
+            { "file": "b.bin", "id": 2, "compressed": false, "exreloc": 4, "imports": [0] },
+            { "file": "c.bin", "id": 3, "compressed": true }
+        ] }"#,
+    );
+}
+
+#[test]
+fn unlink_then_relink_round_trip() {
+    let dir = TestDir::new("round-trip");
+    write_archive_inputs(&dir);
+    dir.halld_ok(&[
+        "-k",
+        "cache",
+        "script.json",
+        "--format",
+        "bin",
+        "-o",
+        "linked.bin",
+    ]);
+
+    dir.halld_ok(&["unlink", "linked.bin", "-o", "un", "-k", "cache"]);
+    let script: serde_json::Value = serde_json::from_slice(&dir.read("un/script.json")).unwrap();
+    let files = script["script"].as_array().unwrap();
+    let field = |key: &str| files.iter().map(|f| f[key].clone()).collect::<Vec<_>>();
+    assert_eq!(field("id"), [0, 2, 3]);
+    assert_eq!(
+        field("inreloc"),
+        [0.into(), serde_json::Value::Null, serde_json::Value::Null]
+    );
+    assert_eq!(
+        field("exreloc"),
+        [serde_json::Value::Null, 4.into(), serde_json::Value::Null]
+    );
+    assert_eq!(files[1]["imports"], serde_json::json!([0]));
+    assert_eq!(files[2]["compressed"], true);
+    assert_eq!(dir.read("un/0000.bin"), dir.read("a.bin"));
+    assert_eq!(dir.read("un/0003.bin"), dir.read("c.bin"));
+
+    dir.halld_ok(&["un/script.json", "--format", "bin", "-o", "relinked.bin"]);
+    assert_eq!(dir.read("relinked.bin"), dir.read("linked.bin"));
+}
+
+#[test]
+fn unlink_reads_a_table_inside_a_larger_binary() {
+    let dir = TestDir::new("table-at");
+    write_archive_inputs(&dir);
+    dir.halld_ok(&[
+        "-k",
+        "cache",
+        "script.json",
+        "--format",
+        "bin",
+        "-o",
+        "linked.bin",
+    ]);
+
+    let mut rom = vec![0xAA; 0x40];
+    rom.extend(dir.read("linked.bin"));
+    rom.extend([0xBB; 0x40]);
+    dir.write("rom.bin", rom);
+
+    // the table has four entries, one of them empty, then the terminator
+    dir.halld_ok(&["unlink", "rom.bin", "-t", "0x40", "-o", "un", "-k", "cache"]);
+    dir.halld_ok(&[
+        "unlink", "rom.bin", "-t", "0x40", "-n", "4", "-o", "counted",
+    ]);
+    for id in ["0000", "0002", "0003"] {
+        let name = format!("{}.bin", id);
+        assert_eq!(
+            dir.read(&format!("un/{}", name)),
+            dir.read(&format!("counted/{}", name))
+        );
+    }
+    assert_eq!(dir.read("un/0002.bin"), dir.read("b.bin"));
+    assert!(!dir.0.join("un/0001.bin").exists());
+
+    dir.halld_ok(&["un/script.json", "--format", "bin", "-o", "relinked.bin"]);
+    assert_eq!(dir.read("relinked.bin"), dir.read("linked.bin"));
+}
+
+/// A raw file table with one uncompressed file of `data`, and the terminator
+fn raw_archive(inreloc: u16, data: &[u32]) -> Vec<u8> {
+    let len = data.len() as u32 * 4;
+    let halves = [inreloc, data.len() as u16, 0xFFFF, data.len() as u16];
+
+    let mut archive = 0u32.to_be_bytes().to_vec();
+    archive.extend(halves.iter().flat_map(|h| h.to_be_bytes()));
+    archive.extend(len.to_be_bytes());
+    archive.extend([0; 8]);
+    archive.extend(words(data));
+    archive
+}
+
+#[test]
+fn broken_relocation_chains_are_reported() {
+    let dir = TestDir::new("broken-chain");
+
+    // word 1 points back to word 0
+    dir.write("loop.bin", raw_archive(0, &[0x0001_0000, 0x0000_0000]));
+    let out = dir.halld(&["unlink", "loop.bin", "-o", "un"]);
+    assert!(!out.status.success());
+    assert!(
+        stderr(&out).contains("relocation chain loops back to 0x0"),
+        "{}",
+        stderr(&out)
+    );
+
+    // word 0 points past the end of the file
+    dir.write("past.bin", raw_archive(0, &[0x0004_0000]));
+    let out = dir.halld(&["unlink", "past.bin", "-o", "un"]);
+    assert!(!out.status.success());
+    assert!(
+        stderr(&out).contains("relocation at 0x10 is outside of file data"),
+        "{}",
+        stderr(&out)
+    );
+}
+
+fn stdout(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stdout).into_owned()
+}
+
+#[test]
+fn verify_reports_every_mismatch() {
+    let dir = TestDir::new("verify");
+    write_archive_inputs(&dir);
+    dir.halld_ok(&[
+        "-k",
+        "cache",
+        "script.json",
+        "--format",
+        "bin",
+        "-o",
+        "ref.bin",
+    ]);
+
+    let out = dir.halld_ok(&[
+        "-k",
+        "cache",
+        "script.json",
+        "--verify",
+        "ref.bin",
+        "-o",
+        "same.o",
+    ]);
+    assert_eq!(stdout(&out), "");
+
+    // change a word, and part of the compressed file
+    dir.write(
+        "a.bin",
+        words(&[0x0001_0003, 0xFFFF_0002, 0x1111_1111, 0x2222_2223]),
+    );
+    let mut c = dir.read("c.bin");
+    c[0x100] ^= 0xFF;
+    dir.write("c.bin", c);
+    let out = dir.halld(&["script.json", "--verify", "ref.bin", "-o", "changed.o"]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("2 files did not match the reference"));
+    let report = stdout(&out);
+    assert!(report.contains("file 0 < a.bin > does not match:\n    data differs at 0xf\n"));
+    assert!(report.contains("file 3 < c.bin > does not match:"));
+    assert!(report.contains("uncompressed data differs at 0x100"));
+    assert!(!report.contains("file 2"));
+
+    // a different import, and a new file
+    dir.write(
+        "script.json",
+        r#"{ "script": [
+            { "file": "a.bin", "id": 0, "compressed": false, "inreloc": 0 },
+            { "file": "b.bin", "id": 2, "compressed": false, "exreloc": 4, "imports": [3] },
+            { "file": "c.bin", "id": 3, "compressed": true },
+            { "file": "b.bin", "id": 4, "compressed": false }
+        ] }"#,
+    );
+    let out = dir.halld(&["script.json", "--verify", "ref.bin", "-o", "changed.o"]);
+    assert!(!out.status.success());
+    let report = stdout(&out);
+    assert!(report.contains("linked 5 files, but the reference has 4 files"));
+    assert!(report.contains("extern file ids [3] vs reference [0]"));
+}
+
+#[test]
+fn verify_has_no_short_flag() {
+    let dir = TestDir::new("verify-flag");
+    write_archive_inputs(&dir);
+    dir.halld_ok(&["script.json", "--format", "bin", "-o", "ref.bin"]);
+
+    // -v is left for a version or verbose flag
+    let out = dir.halld(&["script.json", "-v", "ref.bin", "-o", "out.o"]);
+    assert!(!out.status.success());
+    assert!(
+        stderr(&out).contains("unexpected arguments"),
+        "{}",
+        stderr(&out)
+    );
+}
+
+#[test]
+fn bad_data_output_writes_nothing() {
+    let dir = TestDir::new("data-output");
+    write_archive_inputs(&dir);
+
+    for args in [
+        &[
+            "--format",
+            "elf",
+            "--data-output",
+            "data.bin",
+            "-o",
+            "out.o",
+        ][..],
+        &["--format", "c", "--data-output", "data.bin", "-o", "out.c"],
+        &["--format", "asm", "--data-output", "out.s", "-o", "out.s"],
+    ] {
+        let out = dir.halld(&[&["script.json", "-m", "map.txt"], args].concat());
+        assert!(!out.status.success(), "halld {:?} succeeded", args);
+        for name in ["out.o", "out.c", "out.s", "data.bin", "map.txt"] {
+            assert!(!dir.0.join(name).exists(), "{} was written", name);
+        }
+    }
+
+    // asm output puts the file data next to it by default
+    dir.halld_ok(&["script.json", "--format", "asm", "-o", "out.s"]);
+    assert!(dir.0.join("out.bin").exists());
+}
+
+/// A big endian ROM with enough data for the header checksums, from `byte(offset)`
+fn rom(byte: impl Fn(u32) -> u8) -> Vec<u8> {
+    let mut rom = (0..0x10_1000).map(byte).collect::<Vec<_>>();
+    rom[..4].copy_from_slice(&[0x80, 0x37, 0x12, 0x40]);
+    rom
+}
+
+/// The two checksums in a ROM header
+fn rom_crcs(rom: &[u8]) -> (u32, u32) {
+    let word = |at: usize| u32::from_be_bytes(rom[at..at + 4].try_into().unwrap());
+    (word(0x10), word(0x14))
+}
+
+#[test]
+fn inject_updates_the_checksums() {
+    let dir = TestDir::new("inject-crc");
+    write_archive_inputs(&dir);
+    // inject past the checksummed data, so the expected checksums only depend on `rom`
+    let inject = |rom: &str, cic: &str| {
+        dir.halld_ok(&[
+            "inject",
+            "script.json",
+            "--rom",
+            rom,
+            "--table-at",
+            "0x101000",
+            "--at",
+            "0x101100",
+            "--crc",
+            cic,
+            "-o",
+            "out.z64",
+        ]);
+        rom_crcs(&dir.read("out.z64"))
+    };
+
+    // every word is zero, so only the seed and the word count matter
+    dir.write("zero.z64", rom(|_| 0));
+    assert_eq!(inject("zero.z64", "6102"), (0xF8CA_4DDC, 0x303A_4DDC));
+
+    // expected checksums are from a separate port of n64crc.c
+    dir.write(
+        "pattern.z64",
+        rom(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8),
+    );
+    assert_eq!(inject("pattern.z64", "6102"), (0x25FB_A505, 0xF4CA_2D8F));
+    assert_eq!(
+        inject("pattern.z64", "CIC-NUS-6102"),
+        (0x25FB_A505, 0xF4CA_2D8F)
+    );
+    assert_eq!(inject("pattern.z64", "6105"), (0x1BAE_1F5F, 0x1212_104E));
+    assert_eq!(inject("pattern.z64", "6106"), (0xC9EE_2EAE, 0x4F65_BD11));
+
+    for (cic, error) in [("6104", "unknown CIC 6104"), ("NUS", "invalid CIC \"NUS\"")] {
+        let out = dir.halld(&[
+            "inject",
+            "script.json",
+            "--rom",
+            "zero.z64",
+            "--table-at",
+            "0x101000",
+            "--at",
+            "0x101100",
+            "--crc",
+            cic,
+        ]);
+        assert!(!out.status.success());
+        assert!(stderr(&out).contains(error), "{}", stderr(&out));
+    }
+}
+
+#[test]
+fn inject_checks_overlaps_and_the_limit() {
+    let dir = TestDir::new("inject-regions");
+    write_archive_inputs(&dir);
+    dir.write("rom.z64", rom(|_| 0xFF));
+    let inject = |table_at: &str, at: &str, limit: &str| {
+        dir.halld(&[
+            "inject",
+            "script.json",
+            "--rom",
+            "rom.z64",
+            "--table-at",
+            table_at,
+            "--at",
+            at,
+            "--limit",
+            limit,
+            "-k",
+            "cache",
+        ])
+    };
+    dir.halld_ok(&[
+        "-k",
+        "cache",
+        "script.json",
+        "--format",
+        "bin",
+        "-o",
+        "linked.bin",
+    ]);
+    // four entries, and the terminator
+    let table_len = 5 * 12;
+    let data_len = dir.read("linked.bin").len() - table_len;
+
+    let out = inject("0x1000", "0x1010", "0x10000");
+    assert!(!out.status.success());
+    assert!(
+        stderr(&out).contains("the file table at 0x1000-0x103c overlaps the file data"),
+        "{}",
+        stderr(&out)
+    );
+
+    let end = 0x2000 + data_len;
+    let out = inject("0x1000", "0x2000", &format!("{:#x}", end - 1));
+    assert!(!out.status.success());
+    assert!(
+        stderr(&out).contains("would write past the limit of")
+            && stderr(&out).contains("by 0x1 bytes"),
+        "{}",
+        stderr(&out)
+    );
+    assert_eq!(dir.read("rom.z64"), rom(|_| 0xFF));
+
+    // ending right at the limit is fine
+    let out = inject("0x1000", "0x2000", &format!("{:#x}", end));
+    assert!(out.status.success(), "{}", stderr(&out));
+    let rom = dir.read("rom.z64");
+    let linked = dir.read("linked.bin");
+    assert_eq!(rom[0x1000..0x1000 + table_len], linked[..table_len]);
+    assert_eq!(rom[0x2000..end], linked[table_len..]);
+    assert_eq!(rom[end], 0xFF);
+}
+
+#[test]
+fn clashing_file_symbols_are_diagnostics() {
+    let dir = TestDir::new("file-symbols");
+    dir.write("a.bin", [0; 4]);
+    dir.write("a.dat", [0; 4]);
+    dir.write("b.bin", [0; 4]);
+    dir.write(
+        "script.json",
+        r#"{
+            "settings": { "object": { "fileSymbols": {} } },
+            "script": [
+                { "file": "a.bin", "compressed": false },
+                { "file": "a.dat", "compressed": false },
+                { "file": "b.bin", "compressed": false, "exports": [["RLD_FID_B_END", 0]] }
+            ]
+        }"#,
+    );
+
+    let out = dir.halld(&["script.json", "--diagnostics", "diag.json", "-o", "out.o"]);
+    assert!(!out.status.success());
+    let diags: serde_json::Value = serde_json::from_slice(&dir.read("diag.json")).unwrap();
+    let symbols = diags
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|d| {
+            assert_eq!(d["kind"], "duplicateSymbol");
+            d["symbol"].as_str().unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        symbols,
+        [
+            "RLD_FID_A_START",
+            "RLD_FID_A_END",
+            "RLD_FID_A_ENTRY",
+            "RLD_FID_B_END"
+        ]
+    );
+    assert_eq!(diags[0]["first"], "a.bin");
+    assert_eq!(diags[0]["redefined"], "a.dat");
+    assert!(!dir.0.join("out.o").exists());
+}
+
+#[test]
+fn diagnostics_file_is_only_written_for_diagnostics() {
+    let dir = TestDir::new("diagnostics");
+    write_archive_inputs(&dir);
+
+    dir.halld_ok(&["script.json", "--diagnostics", "diag.json", "-o", "out.o"]);
+    assert_eq!(dir.read("diag.json"), b"[]");
+
+    // a missing input isn't a diagnostic, so the earlier empty array is removed
+    fs::remove_file(dir.0.join("b.bin")).unwrap();
+    let out = dir.halld(&["script.json", "--diagnostics", "diag.json", "-o", "out.o"]);
+    assert!(!out.status.success());
+    assert!(!dir.0.join("diag.json").exists());
+}
+
+/// The names of the files in a cache's entry directory
+fn cache_entries(dir: &TestDir) -> Vec<String> {
+    let mut names = fs::read_dir(dir.0.join("cache/v2"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    names.sort_unstable();
+    names
+}
+
+#[test]
+fn cache_gc_only_reads_the_script() {
+    let dir = TestDir::new("cache-gc");
+    write_archive_inputs(&dir);
+    dir.halld_ok(&["-k", "cache", "script.json", "-o", "out.o"]);
+    let used = cache_entries(&dir);
+    assert_eq!(used.len(), 2);
+
+    dir.write("d.bin", [0x44; 64]);
+    dir.write(
+        "other.json",
+        r#"{ "script": [{ "file": "d.bin", "compressed": true }] }"#,
+    );
+    dir.halld_ok(&["-k", "cache", "other.json", "-o", "other.o"]);
+    assert_eq!(cache_entries(&dir).len(), 4);
+    let last_run = dir.read("cache/last-run.json");
+
+    // a duplicate symbol between uncompressed files, and a compressed file with no entry
+    dir.write("e.bin", [0x55; 64]);
+    dir.write(
+        "script.json",
+        r#"{ "script": [
+            { "file": "a.bin", "id": 0, "compressed": false, "inreloc": 0, "exports": [["dup", 0]] },
+            { "file": "b.bin", "id": 2, "compressed": false, "exreloc": 4, "imports": [0], "exports": [["dup", 0]] },
+            { "file": "c.bin", "id": 3, "compressed": true },
+            { "file": "e.bin", "compressed": true }
+        ] }"#,
+    );
+    let out = dir.halld_ok(&["cache", "gc", "--script", "script.json", "-k", "cache"]);
+    assert!(
+        stdout(&out).contains("removed 1 entries"),
+        "{}",
+        stdout(&out)
+    );
+    assert_eq!(cache_entries(&dir), used);
+    assert_eq!(dir.read("cache/last-run.json"), last_run);
+}
+
+#[test]
+fn cache_commands_dont_create_a_cache() {
+    let dir = TestDir::new("cache-open");
+    for args in [
+        &["cache", "stats", "-k", "cahce"][..],
+        &["cache", "prune", "--max-size", "1M", "-k", "cahce"],
+    ] {
+        let out = dir.halld(args);
+        assert!(!out.status.success());
+        assert!(
+            stderr(&out).contains("opening cache < cahce >"),
+            "{}",
+            stderr(&out)
+        );
+        assert!(!dir.0.join("cahce").exists());
+    }
+
+    // a cache with only entries from before the layout was versioned is still read
+    fs::create_dir(dir.0.join("old")).unwrap();
+    dir.write(&format!("old/{}.bin", "0".repeat(32)), [0; 16]);
+    let out = dir.halld_ok(&["cache", "stats", "-k", "old"]);
+    assert!(stdout(&out).contains("entries: 0"), "{}", stdout(&out));
+    assert!(
+        stdout(&out).contains("1 entries (16 B)"),
+        "{}",
+        stdout(&out)
+    );
+    assert!(!dir.0.join("old/v2").exists());
+}
+
+#[test]
+fn relocatable_object_keeps_the_compressed_flag() {
+    let dir = TestDir::new("relocatable-compressed");
+    write_archive_inputs(&dir);
+    dir.halld_ok(&["-k", "cache", "script.json", "-r", "-o", "r.o"]);
+
+    // the table's implicit addends are the offsets with the compressed flag
+    dir.halld_ok(&["unlink", "r.o", "-o", "un", "-k", "cache"]);
+    let script: serde_json::Value = serde_json::from_slice(&dir.read("un/script.json")).unwrap();
+    let files = script["script"].as_array().unwrap();
+    assert_eq!(files[2]["compressed"], true);
+    assert_eq!(dir.read("un/0003.bin"), dir.read("c.bin"));
+}
+
+#[test]
+fn verify_and_unlink_renamed_sections() {
+    let dir = TestDir::new("renamed-sections");
+    write_archive_inputs(&dir);
+    let script = String::from_utf8(dir.read("script.json")).unwrap();
+    let settings = r#"{ "settings": { "object": {
+        "tableSection": ".stage_table", "filesSection": ".stage_files"
+    } }, "script""#;
+    dir.write("script.json", script.replacen(r#"{ "script""#, settings, 1));
+    dir.halld_ok(&["-k", "cache", "script.json", "-o", "ref.o"]);
+
+    dir.halld_ok(&[
+        "-k",
+        "cache",
+        "script.json",
+        "--verify",
+        "ref.o",
+        "-o",
+        "out.o",
+    ]);
+
+    let out = dir.halld(&["unlink", "ref.o", "-o", "un"]);
+    assert!(
+        stderr(&out).contains("missing .filetable section"),
+        "{}",
+        stderr(&out)
+    );
+    dir.halld_ok(&[
+        "unlink",
+        "ref.o",
+        "--table-section",
+        ".stage_table",
+        "--files-section",
+        ".stage_files",
+        "-o",
+        "un",
+        "-k",
+        "cache",
+    ]);
+    let unlinked: serde_json::Value = serde_json::from_slice(&dir.read("un/script.json")).unwrap();
+    assert_eq!(
+        unlinked["settings"]["object"]["tableSection"],
+        ".stage_table"
+    );
+    dir.halld_ok(&[
+        "-k",
+        "cache",
+        "un/script.json",
+        "--verify",
+        "ref.o",
+        "-o",
+        "relinked.o",
+    ]);
+}
+
+#[test]
+fn unknown_compression_method_is_an_error() {
+    let dir = TestDir::new("bad-method");
+    dir.write("c.bin", [0x11; 64]);
+    dir.write(
+        "script.json",
+        r#"{ "script": [
+            { "file": "c.bin", "compressed": true, "compSettings": { "method": 2 } }
+        ] }"#,
+    );
+
+    let out = dir.halld(&["script.json", "-o", "out.o"]);
+    assert_eq!(out.status.code(), Some(1), "{}", stderr(&out));
+    assert!(
+        stderr(&out).contains("compressing <c.bin>"),
+        "{}",
+        stderr(&out)
+    );
+    assert!(
+        stderr(&out).contains("unknown vpk0 method 2"),
+        "{}",
+        stderr(&out)
+    );
+}
@@ -0,0 +1,1094 @@
+use halld::{
+    BoundarySymbols, Diagnostic, Diagnostics, Export, ExportedSymbol, FileRef, FileSymbols,
+    FileType, InputFile, LinkOutput, Linker, LinkerConfig, ObjectSettings, SymKind, SymScope,
+    SymbolAlias,
+};
+use object::{
+    write::{Object, Relocation, Symbol, SymbolSection},
+    Architecture, BinaryFormat, Endianness, RelocationEncoding, RelocationKind, SectionKind,
+    SymbolFlags, SymbolKind, SymbolScope,
+};
+use std::{fs, path::PathBuf};
+
+enum Target<'a> {
+    /// offset into the same file
+    Internal(u32),
+    /// symbol name and addend
+    External(&'a str, u32),
+}
+
+/// An input file to write out before linking
+struct TestFile {
+    bytes: Vec<u8>,
+    ext: &'static str,
+    id: Option<u16>,
+    exports: Option<Vec<Export>>,
+    imports: Option<Vec<FileRef>>,
+    /// JSON for a relocation file
+    relocs: Option<&'static str>,
+    file_type: Option<FileType>,
+}
+
+/// A raw binary input with its exported symbols
+fn raw(data: &[u8], exports: &[(&str, u32)]) -> TestFile {
+    TestFile {
+        bytes: data.to_vec(),
+        ext: "bin",
+        id: None,
+        exports: Some(
+            exports
+                .iter()
+                .map(|&(s, v)| Export::Offset(s.to_string(), v))
+                .collect(),
+        ),
+        imports: None,
+        relocs: None,
+        file_type: None,
+    }
+}
+
+/// An ELF object input with no script settings
+fn obj_file(bytes: Vec<u8>) -> TestFile {
+    TestFile {
+        bytes,
+        ext: "o",
+        id: None,
+        exports: None,
+        imports: None,
+        relocs: None,
+        file_type: None,
+    }
+}
+
+/// Assemble a MIPS relocatable object with `data` in `.data`
+fn elf(data: &[u8], relocs: &[(u64, Target)]) -> TestFile {
+    let mut obj = Object::new(BinaryFormat::Elf, Architecture::Mips, Endianness::Big);
+    let sec = obj.add_section(Vec::new(), b".data".to_vec(), SectionKind::Data);
+    obj.set_section_data(sec, data.to_vec(), 4);
+
+    for (offset, target) in relocs {
+        let (symbol, addend) = match *target {
+            Target::Internal(to) => (obj.section_symbol(sec), to),
+            Target::External(name, addend) => {
+                let symbol = obj.symbol_id(name.as_bytes()).unwrap_or_else(|| {
+                    obj.add_symbol(Symbol {
+                        name: name.as_bytes().to_vec(),
+                        value: 0,
+                        size: 0,
+                        kind: SymbolKind::Unknown,
+                        scope: SymbolScope::Linkage,
+                        weak: false,
+                        section: SymbolSection::Undefined,
+                        flags: SymbolFlags::None,
+                    })
+                });
+                (symbol, addend)
+            }
+        };
+        let reloc = Relocation {
+            offset: *offset,
+            size: 32,
+            kind: RelocationKind::Absolute,
+            encoding: RelocationEncoding::Generic,
+            symbol,
+            addend: addend.into(),
+        };
+        obj.add_relocation(sec, reloc).unwrap();
+    }
+
+    obj_file(obj.write().unwrap())
+}
+
+/// A raw binary input that imports other files
+fn importer(imports: Vec<FileRef>) -> TestFile {
+    TestFile {
+        imports: Some(imports),
+        ..raw(&[0; 4], &[])
+    }
+}
+
+/// Write each file into a fresh directory as `<index>.<ext>` and link them in order
+fn link(test: &str, files: Vec<TestFile>) -> anyhow::Result<LinkOutput> {
+    let dir = std::env::temp_dir().join(format!("halld-{}-{}", test, std::process::id()));
+    fs::create_dir_all(&dir)?;
+
+    let script = files
+        .into_iter()
+        .enumerate()
+        .map(|(i, f)| {
+            let file = PathBuf::from(format!("{}.{}", i, f.ext));
+            fs::write(dir.join(&file), f.bytes)?;
+            if let Some(relocs) = f.relocs {
+                fs::write(dir.join(format!("{}.relocs.json", i)), relocs)?;
+            }
+            Ok(InputFile {
+                file,
+                id: f.id,
+                compressed: false,
+                comp_settings: None,
+                inreloc: None,
+                exreloc: None,
+                exports: f.exports,
+                imports: f.imports,
+                relocs: f
+                    .relocs
+                    .map(|_| PathBuf::from(format!("{}.relocs.json", i))),
+                file_type: f.file_type,
+            })
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let config = LinkerConfig {
+        settings: None,
+        script,
+    };
+    let output = Linker::new(config).search_dir(&dir).link();
+    fs::remove_dir_all(&dir)?;
+
+    output
+}
+
+/// (offset, inreloc, rom size, exreloc, size) of table entry `id`
+fn entry(out: &LinkOutput, id: usize) -> (u32, u16, u16, u16, u16) {
+    let e = &out.table[id * 12..id * 12 + 12];
+    let half = |at: usize| u16::from_be_bytes([e[at], e[at + 1]]);
+
+    (
+        u32::from_be_bytes(e[0..4].try_into().unwrap()),
+        half(4),
+        half(6),
+        half(8),
+        half(10),
+    )
+}
+
+fn word(out: &LinkOutput, at: u32) -> u32 {
+    let at = at as usize;
+    u32::from_be_bytes(out.data[at..at + 4].try_into().unwrap())
+}
+
+fn externs(out: &LinkOutput, id: usize) -> Vec<u16> {
+    let (offset, _, rom_size, ..) = entry(out, id);
+    let next = u32::from_be_bytes(out.table[id * 12 + 12..id * 12 + 16].try_into().unwrap());
+    let start = (offset + u32::from(rom_size) * 4) as usize;
+
+    out.data[start..next as usize]
+        .chunks_exact(2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .collect()
+}
+
+#[test]
+fn internal_relocations_only() {
+    let obj = elf(
+        &[0; 16],
+        &[(0x0, Target::Internal(0x8)), (0xC, Target::Internal(0x4))],
+    );
+    let out = link("internal", vec![obj]).unwrap();
+
+    assert_eq!(entry(&out, 0), (0, 0, 4, 0xFFFF, 4));
+    assert_eq!(word(&out, 0x0), 0x0003_0002);
+    assert_eq!(word(&out, 0xC), 0xFFFF_0001);
+    assert!(externs(&out, 0).is_empty());
+}
+
+#[test]
+fn external_relocations_only() {
+    let target = raw(&[0; 16], &[("other", 0x4), ("target", 0x8)]);
+    // relocations out of order, and with an addend
+    let importer = elf(
+        &[0; 8],
+        &[
+            (0x4, Target::External("target", 0)),
+            (0x0, Target::External("other", 4)),
+        ],
+    );
+    let out = link("external", vec![target, importer]).unwrap();
+
+    assert_eq!(entry(&out, 1), (16, 0xFFFF, 2, 0, 2));
+    assert_eq!(word(&out, 16), 0x0001_0002);
+    assert_eq!(word(&out, 20), 0xFFFF_0002);
+    assert_eq!(externs(&out, 1), [0, 0]);
+    // the extern list comes right after the file data
+    assert_eq!(out.data.len(), 16 + 8 + 4);
+}
+
+#[test]
+fn negative_implicit_addend() {
+    let target = raw(&[0; 16], &[("end", 0x10)]);
+    // a pointer to the last word, like `end - 4`
+    let importer = elf(&[0; 4], &[(0x0, Target::External("end", -4i32 as u32))]);
+    let out = link("negative-addend", vec![target, importer]).unwrap();
+
+    assert_eq!(word(&out, 16), 0xFFFF_0003);
+    assert_eq!(externs(&out, 1), [0]);
+}
+
+#[test]
+fn mixed_relocations() {
+    let first = raw(&[0; 8], &[("first", 0x4)]);
+    let second = raw(&[0; 4], &[("second", 0x0)]);
+    let mixed = elf(
+        &[0; 16],
+        &[
+            (0x0, Target::External("second", 0)),
+            (0x4, Target::Internal(0xC)),
+            (0x8, Target::External("first", 0)),
+            (0xC, Target::Internal(0x0)),
+        ],
+    );
+    let out = link("mixed", vec![first, second, mixed]).unwrap();
+
+    assert_eq!(entry(&out, 2), (12, 1, 4, 0, 4));
+    assert_eq!(word(&out, 12), 0x0002_0000);
+    assert_eq!(word(&out, 16), 0x0003_0003);
+    assert_eq!(word(&out, 20), 0xFFFF_0001);
+    assert_eq!(word(&out, 24), 0xFFFF_0000);
+    assert_eq!(externs(&out, 2), [1, 0]);
+}
+
+#[test]
+fn missing_external_symbol() {
+    let obj = elf(&[0; 4], &[(0x0, Target::External("missing", 0))]);
+    let err = link("missing", vec![obj]).unwrap_err();
+
+    assert!(format!("{:#}", err).contains("missing"));
+}
+
+#[test]
+fn every_problem_is_reported() {
+    let undefined_raw = TestFile {
+        relocs: Some(r#"[{ "at": 0, "symbol": "nowhere" }]"#),
+        ..raw(&[0; 4], &[])
+    };
+    let files = vec![
+        raw(&[0; 4], &[("dup", 0)]),
+        raw(&[0; 4], &[("dup", 0)]),
+        elf(
+            &[0; 8],
+            &[
+                (0, Target::External("missing", 0)),
+                (4, Target::External("also_missing", 0)),
+            ],
+        ),
+        undefined_raw,
+        importer(vec![FileRef::Id(9)]),
+    ];
+    let err = link("every-problem", files).unwrap_err();
+    let Diagnostics(found) = err.downcast_ref::<Diagnostics>().unwrap();
+
+    assert_eq!(found.len(), 5, "{:#?}", found);
+    assert!(matches!(
+        &found[0],
+        Diagnostic::UnresolvedImport { import, .. } if import == "9"
+    ));
+    assert!(matches!(
+        &found[1],
+        Diagnostic::DuplicateSymbol { symbol, redefined, .. }
+            if symbol == "dup" && redefined.ends_with("1.bin")
+    ));
+    assert!(matches!(
+        &found[2],
+        Diagnostic::UndefinedSymbol { symbol, section: Some(section), offset: 0, relocation: Some(r), .. }
+            if symbol == "missing" && section == ".data" && r == "R_MIPS_32"
+    ));
+    assert!(matches!(
+        &found[3],
+        Diagnostic::UndefinedSymbol { symbol, offset: 4, .. } if symbol == "also_missing"
+    ));
+    assert!(matches!(
+        &found[4],
+        Diagnostic::UndefinedSymbol { symbol, section: None, relocation: None, .. }
+            if symbol == "nowhere"
+    ));
+
+    let json = serde_json::to_value(&found[1]).unwrap();
+    assert_eq!(json["kind"], "duplicateSymbol");
+    assert_eq!(json["symbol"], "dup");
+}
+
+#[test]
+fn merged_sections() {
+    let mut obj = Object::new(BinaryFormat::Elf, Architecture::Mips, Endianness::Big);
+    let data = obj.add_section(Vec::new(), b".data".to_vec(), SectionKind::Data);
+    let rodata = obj.add_section(Vec::new(), b".rodata".to_vec(), SectionKind::ReadOnlyData);
+    let bss = obj.add_section(Vec::new(), b".bss".to_vec(), SectionKind::UninitializedData);
+    obj.set_section_data(data, vec![0; 4], 4);
+    obj.set_section_data(rodata, vec![0xAA, 0xAA, 0xAA, 0xAA, 0, 0, 0, 0], 8);
+    obj.append_section_bss(bss, 8, 4);
+    let buf = obj.add_symbol(Symbol {
+        name: b"buf".to_vec(),
+        value: 0,
+        size: 8,
+        kind: SymbolKind::Data,
+        scope: SymbolScope::Compilation,
+        weak: false,
+        section: SymbolSection::Section(bss),
+        flags: SymbolFlags::None,
+    });
+
+    // .data -> .rodata + 4, and .rodata -> a label in .bss
+    let relocs = [
+        (data, 0, obj.section_symbol(rodata), 4),
+        (rodata, 4, buf, 0),
+    ];
+    for (sec, offset, symbol, addend) in relocs {
+        let reloc = Relocation {
+            offset,
+            size: 32,
+            kind: RelocationKind::Absolute,
+            encoding: RelocationEncoding::Generic,
+            symbol,
+            addend,
+        };
+        obj.add_relocation(sec, reloc).unwrap();
+    }
+    let file = obj_file(obj.write().unwrap());
+    let out = link("sections", vec![file]).unwrap();
+
+    // .data at 0, .rodata at 8, .bss at 16
+    assert_eq!(entry(&out, 0), (0, 0, 6, 0xFFFF, 6));
+    assert_eq!(word(&out, 0), 0x0003_0003);
+    assert_eq!(word(&out, 8), 0xAAAA_AAAA);
+    assert_eq!(word(&out, 12), 0xFFFF_0004);
+    assert_eq!(&out.data[16..24], &[0; 8]);
+}
+
+/// Assemble `lui`/`addiu` pairs in `.data` with `R_MIPS_HI16`/`R_MIPS_LO16` relocations
+/// against `symbol`, which is absolute if it has a value
+fn hi_lo_elf(symbol: &str, value: Option<u64>) -> TestFile {
+    let mut obj = Object::new(BinaryFormat::Elf, Architecture::Mips, Endianness::Big);
+    let sec = obj.add_section(Vec::new(), b".data".to_vec(), SectionKind::Data);
+    // lui $a0, 0; addiu $a0, $a0, 0
+    let code = [0x3C04_0000u32, 0x2484_0000];
+    let code = code
+        .iter()
+        .flat_map(|i| i.to_be_bytes())
+        .collect::<Vec<_>>();
+    obj.set_section_data(sec, code, 4);
+
+    let symbol = obj.add_symbol(Symbol {
+        name: symbol.as_bytes().to_vec(),
+        value: value.unwrap_or(0),
+        size: 0,
+        kind: SymbolKind::Data,
+        scope: SymbolScope::Linkage,
+        weak: false,
+        section: match value {
+            Some(_) => SymbolSection::Absolute,
+            None => SymbolSection::Section(sec),
+        },
+        flags: SymbolFlags::None,
+    });
+    for (offset, r_type) in [(0, object::elf::R_MIPS_HI16), (4, object::elf::R_MIPS_LO16)] {
+        let reloc = Relocation {
+            offset,
+            size: 0,
+            kind: RelocationKind::Elf(r_type),
+            encoding: RelocationEncoding::Generic,
+            symbol,
+            addend: 0,
+        };
+        obj.add_relocation(sec, reloc).unwrap();
+    }
+
+    obj_file(obj.write().unwrap())
+}
+
+#[test]
+fn hi_lo_against_absolute_symbol() {
+    let out = link("hilo-abs", vec![hi_lo_elf("ABS", Some(0x8001_8004))]).unwrap();
+
+    // the low half is negative, so the high half is rounded up
+    assert_eq!(word(&out, 0), 0x3C04_8002);
+    assert_eq!(word(&out, 4), 0x2484_8004);
+    assert_eq!(entry(&out, 0), (0, 0xFFFF, 2, 0xFFFF, 2));
+}
+
+#[test]
+fn hi_lo_against_file_data() {
+    let err = link("hilo-local", vec![hi_lo_elf("local", None)]).unwrap_err();
+    let msg = format!("{:#}", err);
+
+    assert!(msg.contains(".data+0x0: R_MIPS_HI16 against <local>"));
+    assert!(msg.contains(".data+0x4: R_MIPS_LO16 against <local>"));
+}
+
+#[test]
+fn elf_detected_by_contents() {
+    let mut obj = elf(&[0; 8], &[(0x4, Target::Internal(0x0))]);
+    obj.ext = "obj";
+    let out = link("detect", vec![obj]).unwrap();
+
+    assert_eq!(entry(&out, 0), (0, 1, 2, 0xFFFF, 2));
+}
+
+#[test]
+fn raw_type_overrides_elf_contents() {
+    let mut obj = elf(&[0; 8], &[]);
+    let size = obj.bytes.len();
+    obj.file_type = Some(FileType::Raw);
+    let out = link("raw-type", vec![obj]).unwrap();
+
+    assert_eq!(out.data.len(), (size + 3) & !3);
+}
+
+#[test]
+fn little_endian_elf_is_rejected() {
+    let mut obj = Object::new(BinaryFormat::Elf, Architecture::Mips, Endianness::Little);
+    let sec = obj.add_section(Vec::new(), b".data".to_vec(), SectionKind::Data);
+    obj.set_section_data(sec, vec![0; 4], 4);
+    let file = obj_file(obj.write().unwrap());
+    let err = link("little-endian", vec![file]).unwrap_err();
+
+    assert!(format!("{:#}", err).contains("little endian"));
+}
+
+#[test]
+fn imports_by_id_path_define_and_symbol() {
+    let files = vec![
+        raw(&[0; 4], &[]),
+        raw(&[0; 4], &[("second", 0)]),
+        raw(&[0; 4], &[]),
+        importer(vec![
+            FileRef::Id(2),
+            FileRef::Name("0.bin".into()),
+            FileRef::Name("RLD_FID_2".into()),
+            FileRef::Name("second".into()),
+        ]),
+    ];
+    let out = link("imports", files).unwrap();
+
+    assert_eq!(externs(&out, 3), [2, 0, 2, 1]);
+}
+
+#[test]
+fn unresolved_imports_list_closest_files() {
+    let files = vec![
+        raw(&[0; 4], &[]),
+        importer(vec![FileRef::Name("O.bin".into()), FileRef::Id(7)]),
+    ];
+    let err = link("unresolved-imports", files).unwrap_err();
+    let err = format!("{:#}", err);
+
+    assert!(err.contains("2 problems found while linking"), "{}", err);
+    assert!(err.contains("\"O.bin\"; closest files: 0.bin"), "{}", err);
+    assert!(err.contains("no file has id 7"), "{}", err);
+}
+
+#[test]
+fn pinned_ids_leave_empty_entries() {
+    let pinned = TestFile {
+        id: Some(3),
+        ..raw(&[0; 8], &[("pinned", 4)])
+    };
+    let files = vec![
+        raw(&[0; 4], &[]),
+        pinned,
+        importer(vec![FileRef::Name("pinned".into()), FileRef::Id(0)]),
+    ];
+    let out = link("pinned-ids", files).unwrap();
+
+    assert_eq!(entry(&out, 0), (0, 0xFFFF, 1, 0xFFFF, 1));
+    assert_eq!(entry(&out, 1), (4, 0xFFFF, 0, 0xFFFF, 0));
+    assert_eq!(entry(&out, 2), (4, 0xFFFF, 0, 0xFFFF, 0));
+    assert_eq!(entry(&out, 3), (4, 0xFFFF, 2, 0xFFFF, 2));
+    assert_eq!(externs(&out, 4), [3, 0]);
+    assert_eq!(out.symbols["pinned"].file, 3);
+    assert_eq!(out.inputs.iter().filter(|i| i.is_none()).count(), 2);
+    let ids = out.file_ids.iter().map(|(_, id)| *id).collect::<Vec<_>>();
+    assert_eq!(ids, [0, 3, 4]);
+}
+
+#[test]
+fn duplicate_ids_are_rejected() {
+    let pinned = TestFile {
+        id: Some(1),
+        ..raw(&[0; 4], &[])
+    };
+    let files = vec![raw(&[0; 4], &[]), raw(&[0; 4], &[]), pinned];
+    let err = format!("{:#}", link("duplicate-ids", files).unwrap_err());
+
+    assert!(
+        err.contains("File id 1 is used by both < 1.bin > and < 2.bin >"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn raw_relocation_file() {
+    let target = raw(&[0; 8], &[("target", 0x4)]);
+    let relocated = TestFile {
+        relocs: Some(
+            r#"[
+                { "at": 12, "to": 4 },
+                { "at": 8, "symbol": "target", "addend": 4 },
+                { "at": 0, "symbol": "own" },
+                { "at": 4, "symbol": "target" }
+            ]"#,
+        ),
+        ..raw(&[0; 16], &[("own", 0x8)])
+    };
+    let out = link("raw-relocs", vec![target, relocated]).unwrap();
+
+    assert_eq!(entry(&out, 1), (8, 0, 4, 1, 4));
+    assert_eq!(word(&out, 8), 0x0003_0002);
+    assert_eq!(word(&out, 12), 0x0002_0001);
+    assert_eq!(word(&out, 16), 0xFFFF_0002);
+    assert_eq!(word(&out, 20), 0xFFFF_0001);
+    assert_eq!(externs(&out, 1), [0, 0]);
+}
+
+#[test]
+fn raw_relocation_with_negative_addend() {
+    let target = raw(&[0; 8], &[("end", 0x8)]);
+    let relocated = TestFile {
+        relocs: Some(
+            r#"[{ "at": 0, "symbol": "end", "addend": -4 }, { "at": 4, "symbol": "own_end", "addend": -8 }]"#,
+        ),
+        ..raw(&[0; 8], &[("own_end", 0x8)])
+    };
+    let out = link("raw-negative-addend", vec![target, relocated]).unwrap();
+
+    assert_eq!(word(&out, 8), 0xFFFF_0001);
+    assert_eq!(word(&out, 12), 0xFFFF_0000);
+    assert_eq!(externs(&out, 1), [0]);
+}
+
+/// A raw file that defines `name` at `addr` only in its own namespace
+fn local(data: &[u8], name: &str, addr: u32) -> TestFile {
+    TestFile {
+        exports: Some(vec![Export::Symbol(ExportedSymbol {
+            name: name.into(),
+            addr,
+            size: None,
+            kind: None,
+            scope: Some(SymScope::Local),
+            weak: None,
+        })]),
+        ..raw(data, &[])
+    }
+}
+
+#[test]
+fn qualified_symbols_in_file_namespaces() {
+    let own = TestFile {
+        relocs: Some(r#"[{ "at": 0, "symbol": "anim_data" }]"#),
+        ..local(&[0; 8], "anim_data", 4)
+    };
+    let files = vec![
+        local(&[0; 8], "anim_data", 4),
+        local(&[0; 8], "anim_data", 0),
+        elf(
+            &[0; 8],
+            &[
+                (0, Target::External("1.bin::anim_data", 0)),
+                (4, Target::External("RLD_FID_0::anim_data", 0)),
+            ],
+        ),
+        own,
+        importer(vec![FileRef::Name("1.bin::anim_data".into())]),
+    ];
+    let out = link("namespaces", files).unwrap();
+
+    assert!(out.symbols.is_empty());
+    assert_eq!(out.local_symbols.len(), 3);
+    assert_eq!(externs(&out, 2), [1, 0]);
+    assert_eq!(word(&out, 16), 0x0001_0000);
+    assert_eq!(word(&out, 20), 0xFFFF_0001);
+    // its own symbol is an internal relocation
+    assert_eq!(entry(&out, 3).1, 0);
+    assert_eq!(word(&out, 28), 0xFFFF_0001);
+    assert_eq!(externs(&out, 4), [1]);
+
+    // a local symbol can't be found by name alone from another file
+    let files = vec![
+        local(&[0; 8], "anim_data", 4),
+        importer(vec![FileRef::Name("anim_data".into())]),
+        importer(vec![FileRef::Name("0.bin::missing".into())]),
+    ];
+    let err = format!("{:#}", link("namespaces-missing", files).unwrap_err());
+    assert!(
+        err.contains("no file or symbol named \"anim_data\""),
+        "{}",
+        err
+    );
+    assert!(
+        err.contains("\"0.bin\" has no symbol named \"missing\""),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn elf_exports_limit_global_symbols() {
+    let mut obj = Object::new(BinaryFormat::Elf, Architecture::Mips, Endianness::Big);
+    let sec = obj.add_section(Vec::new(), b".data".to_vec(), SectionKind::Data);
+    obj.set_section_data(sec, vec![0; 8], 4);
+    for (name, value) in [("kept", 0), ("anim_data", 4)] {
+        obj.add_symbol(Symbol {
+            name: name.as_bytes().to_vec(),
+            value,
+            size: 4,
+            kind: SymbolKind::Data,
+            scope: SymbolScope::Dynamic,
+            weak: false,
+            section: SymbolSection::Section(sec),
+            flags: SymbolFlags::None,
+        });
+    }
+    let exporter = |exports: &[&str]| TestFile {
+        exports: Some(exports.iter().map(|&e| Export::Name(e.into())).collect()),
+        ..obj_file(obj.write().unwrap())
+    };
+
+    let files = vec![exporter(&["kept"]), raw(&[0; 4], &[("anim_data", 0)])];
+    let out = link("elf-exports", files).unwrap();
+    assert_eq!(out.symbols["kept"].file, 0);
+    assert_eq!(out.symbols["anim_data"].file, 1);
+    assert_eq!(out.local_symbols[0].0, "anim_data");
+    assert_eq!(out.local_symbols[0].1.scope, SymScope::Local);
+
+    // without exports, every global symbol is exported
+    let out = link("elf-exports-none", vec![obj_file(obj.write().unwrap())]).unwrap();
+    assert_eq!(out.symbols["kept"].file, 0);
+    assert_eq!(out.symbols["anim_data"].file, 0);
+    assert!(out.local_symbols.is_empty());
+
+    // an empty list, or one with only aliases, exports nothing else
+    let out = link("elf-exports-empty", vec![exporter(&[])]).unwrap();
+    assert!(out.symbols.is_empty());
+    let aliased = TestFile {
+        exports: Some(vec![Export::Alias(SymbolAlias {
+            name: "renamed".into(),
+            alias_of: "kept".into(),
+            scope: None,
+            weak: None,
+        })]),
+        ..obj_file(obj.write().unwrap())
+    };
+    let out = link("elf-exports-aliases", vec![aliased]).unwrap();
+    assert_eq!(out.symbols.keys().collect::<Vec<_>>(), ["renamed"]);
+
+    let out = link("elf-exports-all", vec![exporter(&["*"])]).unwrap();
+    assert_eq!(out.symbols["kept"].file, 0);
+    assert_eq!(out.symbols["anim_data"].file, 0);
+    assert!(out.local_symbols.is_empty());
+
+    let err = format!(
+        "{:#}",
+        link("elf-exports-missing", vec![exporter(&["nope"])]).unwrap_err()
+    );
+    assert!(
+        err.contains("exports < nope >, but doesn't define it"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn elf_objects_without_exports_share_globals() {
+    let mut obj = Object::new(BinaryFormat::Elf, Architecture::Mips, Endianness::Big);
+    let sec = obj.add_section(Vec::new(), b".data".to_vec(), SectionKind::Data);
+    obj.set_section_data(sec, vec![0; 8], 4);
+    obj.add_symbol(Symbol {
+        name: b"shared".to_vec(),
+        value: 4,
+        size: 4,
+        kind: SymbolKind::Data,
+        scope: SymbolScope::Dynamic,
+        weak: false,
+        section: SymbolSection::Section(sec),
+        flags: SymbolFlags::None,
+    });
+
+    // a script from before `exports` applied to ELF objects
+    let user = elf(&[0; 8], &[(0x4, Target::External("shared", 0))]);
+    let out = link("elf-no-exports", vec![obj_file(obj.write().unwrap()), user]).unwrap();
+    assert_eq!(out.symbols["shared"].file, 0);
+    // the last link in the chain, to word 1 of file 0
+    assert_eq!(word(&out, 8 + 4), 0xFFFF_0001);
+    assert_eq!(externs(&out, 1), [0]);
+}
+
+#[test]
+fn raw_relocations_at_the_same_offset() {
+    let relocated = TestFile {
+        relocs: Some(r#"[{ "at": 0, "to": 4 }, { "at": 0, "to": 0 }]"#),
+        ..raw(&[0; 8], &[])
+    };
+    let err = format!("{:#}", link("raw-relocs-dup", vec![relocated]).unwrap_err());
+
+    assert!(err.contains("more than one relocation at 0x0"), "{}", err);
+}
+
+#[test]
+fn link_map_lists_files_and_symbols() {
+    let target = raw(&[0; 8], &[("b", 0x4), ("a", 0x4)]);
+    let importer = elf(&[0; 4], &[(0x0, Target::External("a", 0))]);
+    let out = link("link-map", vec![target, importer]).unwrap();
+
+    let mut json = Vec::new();
+    out.write_map_json(&mut json).unwrap();
+    let map: serde_json::Value = serde_json::from_slice(&json).unwrap();
+
+    assert_eq!(map[0]["cident"], "RLD_FID_0");
+    assert_eq!(map[0]["symbols"][0]["name"], "a");
+    assert_eq!(map[0]["symbols"][1]["addr"], 4);
+    assert_eq!(map[1]["offset"], 8);
+    assert_eq!(map[1]["exreloc"], 0);
+    assert_eq!(map[1]["inreloc"], serde_json::Value::Null);
+    assert_eq!(map[1]["externs"][0], 0);
+
+    let mut text = Vec::new();
+    out.write_map(&mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.contains("RLD_FID_1 < "), "{}", text);
+}
+
+#[test]
+fn relocatable_object() {
+    use object::{Object as _, ObjectSection, ObjectSymbol};
+
+    let files = vec![
+        raw(&[0; 8], &[("first", 4)]),
+        raw(&[0; 4], &[("second", 0)]),
+    ];
+    let mut out = link("relocatable-object", files).unwrap();
+    out.object.relocatable = Some(true);
+    out.object.boundary_symbols = Some(BoundarySymbols {
+        files_start: Some("__files_start".into()),
+        files_end: Some("__files_end".into()),
+        ..Default::default()
+    });
+
+    let mut bytes = Vec::new();
+    out.write_object(&mut bytes).unwrap();
+    let obj = object::File::parse(&*bytes).unwrap();
+
+    let files = obj.section_by_name(".files").unwrap().index();
+    let table = obj.section_by_name(".filetable").unwrap();
+    let relocs = table.relocations().collect::<Vec<_>>();
+    // two files and the terminator
+    assert_eq!(
+        relocs.iter().map(|(o, _)| *o).collect::<Vec<_>>(),
+        [0, 12, 24]
+    );
+    for (_, reloc) in relocs {
+        assert_eq!((reloc.kind(), reloc.size()), (RelocationKind::Absolute, 32));
+        let object::RelocationTarget::Symbol(idx) = reloc.target() else {
+            panic!("relocation isn't against a symbol");
+        };
+        assert_eq!(
+            obj.symbol_by_index(idx).unwrap().section_index(),
+            Some(files)
+        );
+    }
+
+    let addr = |name: &str| {
+        let sym = obj.symbols().find(|s| s.name() == Ok(name)).unwrap();
+        sym.address()
+    };
+    assert_eq!(addr("first"), 4);
+    assert_eq!(addr("second"), 8);
+    assert_eq!(addr("__files_start"), 0);
+    assert_eq!(addr("__files_end"), 12);
+}
+
+#[test]
+fn renamed_sections_and_boundary_symbols() {
+    use object::{Object as _, ObjectSection, ObjectSymbol, SymbolSection};
+
+    let files = vec![raw(&[0; 8], &[]), raw(&[0; 4], &[])];
+    let mut out = link("renamed-sections", files).unwrap();
+    out.object.table_section = Some(".rld_table".into());
+    out.object.files_section = Some(".rld_data".into());
+    out.object.files_align = Some(16);
+    out.object.boundary_symbols = Some(BoundarySymbols {
+        table_start: Some("rld_table_start".into()),
+        table_end: Some("rld_table_end".into()),
+        file_count: Some("rld_file_count".into()),
+        ..Default::default()
+    });
+
+    let mut bytes = Vec::new();
+    out.write_object(&mut bytes).unwrap();
+    let obj = object::File::parse(&*bytes).unwrap();
+
+    let table = obj.section_by_name(".rld_table").unwrap();
+    assert_eq!(table.size(), 36);
+    assert_eq!(obj.section_by_name(".rld_data").unwrap().align(), 16);
+    assert!(obj.section_by_name(".filetable").is_none());
+
+    let sym = |name: &str| obj.symbols().find(|s| s.name() == Ok(name)).unwrap();
+    assert_eq!(sym("rld_table_start").address(), 0);
+    assert_eq!(sym("rld_table_end").address(), 36);
+    assert_eq!(sym("rld_table_end").section_index(), Some(table.index()));
+    assert_eq!(sym("rld_file_count").address(), 2);
+    assert_eq!(sym("rld_file_count").section(), SymbolSection::Absolute);
+    assert!(obj.symbols().all(|s| s.name() != Ok("__files_start")));
+
+    out.object.files_align = Some(3);
+    assert!(out.write_object(Vec::new()).is_err());
+}
+
+#[test]
+fn boundary_symbols_accept_a_bool() {
+    let parse = |setting: &str| {
+        let json = format!(r#"{{ "boundarySymbols": {} }}"#, setting);
+        serde_json::from_str::<ObjectSettings>(&json)
+            .unwrap()
+            .boundary_symbols
+    };
+
+    let names = parse("true").unwrap();
+    assert_eq!(names.files_start.as_deref(), Some("__files_start"));
+    assert_eq!(names.files_end.as_deref(), Some("__files_end"));
+    assert_eq!(names.table_start, None);
+    assert!(parse("false").is_none());
+    assert!(parse("null").is_none());
+
+    let names = parse(r#"{ "fileCount": "count" }"#).unwrap();
+    assert_eq!(names.file_count.as_deref(), Some("count"));
+    assert_eq!(names.files_start, None);
+
+    let unset = serde_json::from_str::<ObjectSettings>("{}").unwrap();
+    assert!(unset.boundary_symbols.is_none());
+}
+
+#[test]
+fn per_file_symbols() {
+    use object::{Object as _, ObjectSection, ObjectSymbol};
+
+    let files = vec![raw(&[0; 8], &[]), raw(&[0; 4], &[])];
+    let mut out = link("file-symbols", files).unwrap();
+    out.object.file_symbols = Some(FileSymbols {
+        entry: Some("file_{id}_entry".into()),
+        ..Default::default()
+    });
+
+    let mut bytes = Vec::new();
+    out.write_object(&mut bytes).unwrap();
+    let obj = object::File::parse(&*bytes).unwrap();
+
+    let table = obj.section_by_name(".filetable").unwrap().index();
+    let sym = |name: &str| obj.symbols().find(|s| s.name() == Ok(name)).unwrap();
+    assert_eq!(sym("RLD_FID_0_START").address(), 0);
+    assert_eq!(sym("RLD_FID_0_END").address(), 8);
+    assert_eq!(sym("RLD_FID_1_START").address(), 8);
+    assert_eq!(sym("RLD_FID_1_END").address(), 12);
+    assert_eq!(sym("file_1_entry").address(), 12);
+    assert_eq!(sym("file_1_entry").size(), 12);
+    assert_eq!(sym("file_1_entry").section_index(), Some(table));
+
+    out.object.file_symbols = Some(FileSymbols {
+        start: Some("same_name".into()),
+        ..Default::default()
+    });
+    assert!(out.write_object(Vec::new()).is_err());
+}
+
+#[test]
+fn bin_asm_and_c_formats() {
+    let files = vec![raw(&[1; 8], &[("first", 4)]), raw(&[2; 4], &[])];
+    let mut out = link("formats", files).unwrap();
+    out.object.files_align = Some(16);
+    out.object.relocatable = Some(true);
+
+    let mut bin = Vec::new();
+    out.write_bin(&mut bin).unwrap();
+    assert_eq!(&bin[..36], out.table.as_slice());
+    assert_eq!(&bin[36..48], &[0; 12]);
+    assert_eq!(&bin[48..], out.data.as_slice());
+
+    let mut asm = Vec::new();
+    out.write_asm(&mut asm, "files.bin".as_ref()).unwrap();
+    let asm = String::from_utf8(asm).unwrap();
+    assert!(asm.contains(".section .filetable, \"aw\", @progbits\n.balign 4\n"));
+    assert!(asm.contains(".word .Lhalld_files + 0x00000008, 0xffff0001, 0xffff0001"));
+    assert!(asm.contains(".balign 16\n.Lhalld_files:\n    .incbin \"files.bin\""));
+    assert!(asm.contains(".set first, .Lhalld_files + 0x4\n.type first, @object\n.globl first"));
+
+    let mut c = Vec::new();
+    out.write_c(&mut c).unwrap();
+    let c = String::from_utf8(c).unwrap();
+    assert!(c.contains("const unsigned char files[12] __attribute__((section(\".files\"), aligned(16))) = {\n    0x01,"));
+}
+
+#[test]
+fn symbol_metadata() {
+    use object::{Object as _, ObjectSymbol};
+
+    let mut obj = Object::new(BinaryFormat::Elf, Architecture::Mips, Endianness::Big);
+    let sec = obj.add_section(Vec::new(), b".data".to_vec(), SectionKind::Data);
+    obj.set_section_data(sec, vec![0; 16], 4);
+    obj.add_symbol(Symbol {
+        name: b"table".to_vec(),
+        value: 4,
+        size: 8,
+        kind: SymbolKind::Data,
+        scope: SymbolScope::Linkage,
+        weak: false,
+        section: SymbolSection::Section(sec),
+        flags: SymbolFlags::None,
+    });
+    let elf = obj_file(obj.write().unwrap());
+    let mut raw = raw(&[0; 4], &[("plain", 0)]);
+    raw.exports
+        .as_mut()
+        .unwrap()
+        .push(Export::Symbol(ExportedSymbol {
+            name: "private".into(),
+            addr: 0,
+            size: Some(4),
+            kind: Some(SymKind::Text),
+            scope: Some(SymScope::Local),
+            weak: None,
+        }));
+    let out = link("symbol-metadata", vec![elf, raw]).unwrap();
+
+    let table = out.symbols["table"];
+    assert_eq!(
+        (table.addr, table.size, table.kind, table.scope),
+        (4, 8, SymKind::Data, SymScope::Hidden)
+    );
+    let plain = out.symbols["plain"];
+    assert_eq!(
+        (plain.size, plain.kind, plain.scope),
+        (0, SymKind::Data, SymScope::Global)
+    );
+
+    let mut bytes = Vec::new();
+    out.write_object(&mut bytes).unwrap();
+    let obj = object::File::parse(&*bytes).unwrap();
+    let sym = |name: &str| obj.symbols().find(|s| s.name() == Ok(name)).unwrap();
+
+    assert_eq!(sym("table").size(), 8);
+    assert_eq!(sym("table").kind(), SymbolKind::Data);
+    assert_eq!(sym("table").scope(), SymbolScope::Linkage);
+    assert_eq!(sym("private").kind(), SymbolKind::Text);
+    assert!(sym("private").is_local());
+    assert_eq!(sym("plain").scope(), SymbolScope::Dynamic);
+}
+
+#[test]
+fn weak_symbols_and_aliases() {
+    let mut obj = Object::new(BinaryFormat::Elf, Architecture::Mips, Endianness::Big);
+    let sec = obj.add_section(Vec::new(), b".data".to_vec(), SectionKind::Data);
+    obj.set_section_data(sec, vec![0; 8], 4);
+    let anim = obj.add_symbol(Symbol {
+        name: b"anim".to_vec(),
+        value: 0,
+        size: 0,
+        kind: SymbolKind::Data,
+        scope: SymbolScope::Dynamic,
+        weak: true,
+        section: SymbolSection::Section(sec),
+        flags: SymbolFlags::None,
+    });
+    // a pointer to the placeholder's own weak symbol
+    let reloc = Relocation {
+        offset: 4,
+        size: 32,
+        kind: RelocationKind::Absolute,
+        encoding: RelocationEncoding::Generic,
+        symbol: anim,
+        addend: 0,
+    };
+    obj.add_relocation(sec, reloc).unwrap();
+    let alias = |name: &str, of: &str| {
+        Export::Alias(SymbolAlias {
+            name: name.into(),
+            alias_of: of.into(),
+            scope: None,
+            weak: None,
+        })
+    };
+    let placeholder = TestFile {
+        // an alias to an alias that isn't defined yet
+        exports: Some(vec![alias("second_alias", "anim_alias")]),
+        ..obj_file(obj.write().unwrap())
+    };
+    let weak = |name: &str| {
+        Export::Symbol(ExportedSymbol {
+            name: name.into(),
+            addr: 0,
+            size: None,
+            kind: None,
+            scope: None,
+            weak: Some(true),
+        })
+    };
+    let mut fallbacks = raw(&[0; 4], &[]);
+    fallbacks.exports = Some(vec![weak("fallback"), alias("anim_alias", "anim")]);
+    let mut other_fallbacks = raw(&[0; 4], &[]);
+    other_fallbacks.exports = Some(vec![weak("fallback")]);
+
+    let files = vec![
+        placeholder,
+        raw(&[0; 8], &[("anim", 4)]),
+        fallbacks,
+        other_fallbacks,
+    ];
+    let out = link("weak-symbols", files).unwrap();
+
+    // the real asset replaces the placeholder
+    let anim = out.symbols["anim"];
+    assert_eq!((anim.file, anim.addr, anim.weak), (1, 4, false));
+    // even for pointers in the placeholder itself
+    assert_eq!(entry(&out, 0), (0, 0xFFFF, 2, 1, 2));
+    assert_eq!(word(&out, 4), 0xFFFF_0001);
+    assert_eq!(externs(&out, 0), [1]);
+    for name in ["anim_alias", "second_alias"] {
+        assert_eq!((out.symbols[name].file, out.symbols[name].addr), (1, 4));
+    }
+    // the first weak symbol is kept
+    assert_eq!(out.symbols["fallback"].file, 2);
+    assert!(out.symbols["fallback"].weak);
+
+    let mut asm = Vec::new();
+    out.write_asm(&mut asm, "files.bin".as_ref()).unwrap();
+    let asm = String::from_utf8(asm).unwrap();
+    assert!(
+        asm.contains(".type fallback, @object\n.weak fallback"),
+        "{}",
+        asm
+    );
+
+    let mut missing = raw(&[0; 4], &[]);
+    missing.exports = Some(vec![alias("lost", "nothing")]);
+    let err = link("weak-missing-alias", vec![missing]).unwrap_err();
+    let Diagnostics(found) = err.downcast_ref::<Diagnostics>().unwrap();
+    assert!(matches!(
+        found.as_slice(),
+        [Diagnostic::UndefinedAlias { alias, .. }] if alias == "lost"
+    ));
+}
+
+#[test]
+fn aliases_are_in_the_declaring_namespace() {
+    let target = raw(&[0; 8], &[("target", 4)]);
+    let declaring = TestFile {
+        relocs: Some(r#"[{ "at": 0, "symbol": "mine" }]"#),
+        exports: Some(vec![Export::Alias(SymbolAlias {
+            name: "mine".into(),
+            alias_of: "target".into(),
+            scope: Some(SymScope::Local),
+            weak: None,
+        })]),
+        ..raw(&[0; 4], &[])
+    };
+    let qualified = TestFile {
+        relocs: Some(r#"[{ "at": 0, "symbol": "1.bin::mine" }]"#),
+        ..raw(&[0; 4], &[])
+    };
+    let out = link("alias-namespace", vec![target, declaring, qualified]).unwrap();
+
+    assert!(!out.symbols.contains_key("mine"));
+    for id in [1, 2] {
+        let (offset, ..) = entry(&out, id);
+        assert_eq!(word(&out, offset), 0xFFFF_0001);
+        assert_eq!(externs(&out, id), [0]);
+    }
+}